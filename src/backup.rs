@@ -0,0 +1,141 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use eyre::{eyre, Result, WrapErr};
+use rusqlite::{Connection, OpenFlags};
+
+use crate::date::Date;
+
+/// Number of backups to keep. Older ones are deleted.
+static BACKUPS_TO_KEEP: AtomicUsize = AtomicUsize::new(DEFAULT_BACKUPS_TO_KEEP);
+
+pub const DEFAULT_BACKUPS_TO_KEEP: usize = 10;
+
+/// Sets how many backups to keep, or 0 to not make any.
+pub fn set_backups_to_keep(n: usize) {
+    BACKUPS_TO_KEEP.store(n, Ordering::Relaxed);
+}
+
+/// Saves a copy of the database in the backup directory, named after the
+/// current time in UTC, and deletes the oldest backups.
+pub fn create(conn: &Connection) -> Result<()> {
+    let keep = BACKUPS_TO_KEEP.load(Ordering::Relaxed);
+    if keep == 0 {
+        return Ok(());
+    }
+
+    let dir = crate::util::backup_dir();
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("comics-{}.sqlite", timestamp(SystemTime::now())));
+    if path.exists() {
+        // Already backed up this millisecond.
+        return Ok(());
+    }
+
+    // Write to a temporary file first so that there is never an incomplete
+    // backup with a valid name.
+    let tmp_path = path.with_extension("sqlite.tmp");
+    if tmp_path.exists() {
+        std::fs::remove_file(&tmp_path)?;
+    }
+    conn.execute("VACUUM INTO ?1", [tmp_path.to_string_lossy()])
+        .wrap_err("error backing up data store")?;
+    std::fs::rename(&tmp_path, &path)?;
+
+    for old in list().into_iter().skip(keep) {
+        if let Err(e) = std::fs::remove_file(&old) {
+            eprintln!("error removing old backup {}: {e}", old.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the backups, newest first.
+pub fn list() -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(crate::util::backup_dir()) else {
+        return vec![];
+    };
+    let mut backups: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "sqlite"))
+        .collect();
+    // Timestamps sort the same way as the times they stand for.
+    backups.sort();
+    backups.reverse();
+    backups
+}
+
+/// Returns the backup called `name`, with or without its extension.
+pub fn find(name: &str) -> Result<PathBuf> {
+    list()
+        .into_iter()
+        .find(|path| {
+            path.file_name().is_some_and(|s| s == name)
+                || path.file_stem().is_some_and(|s| s == name)
+        })
+        .ok_or_else(|| eyre!("no backup named {name:?}"))
+}
+
+/// Returns the number of comics in the backup at `path` and how many are in
+/// each tier, indexed by tier.
+fn tier_counts(path: &Path) -> Result<(usize, Vec<usize>)> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let comics: usize = conn.query_row("SELECT COUNT(*) FROM comics", [], |row| row.get(0))?;
+    let mut counts = vec![0; crate::TIERS.len()];
+    let mut stmt = conn.prepare("SELECT tier, COUNT(*) FROM tier_assignments GROUP BY tier")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let tier: usize = row.get(0)?;
+        if let Some(count) = counts.get_mut(tier) {
+            *count = row.get(1)?;
+        }
+    }
+    // Comics without an assignment are unsorted.
+    let sorted: usize = counts.iter().sum();
+    counts[0] += comics.saturating_sub(sorted);
+    Ok((comics, counts))
+}
+
+/// Prints the backups, newest first, with how many comics are in each tier.
+pub fn print_list() {
+    let backups = list();
+    if backups.is_empty() {
+        println!("No backups in {}", crate::util::backup_dir().display());
+        return;
+    }
+    println!("Backups, newest first (times are UTC):");
+    for path in backups {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        match tier_counts(&path) {
+            Ok((comics, counts)) => {
+                let tiers: Vec<String> = crate::TIERS
+                    .iter()
+                    .zip(counts)
+                    .filter(|&(_, count)| count > 0)
+                    .map(|(tier, count)| format!("{} {count}", tier.title))
+                    .collect();
+                println!("  {name}  {comics} comics  {}", tiers.join(", "));
+            }
+            Err(e) => println!("  {name}  unreadable: {e}"),
+        }
+    }
+}
+
+/// Formats `time` in UTC for a file name, such as `20261017-153000-250`.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let date = Date::from_days_since_epoch(secs / 86_400);
+    let secs_of_day = secs % 86_400;
+    format!(
+        "{}-{:02}{:02}{:02}-{:03}",
+        date.to_string().replace('-', ""),
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
@@ -0,0 +1,55 @@
+use eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+/// User settings, loaded from `config.json`. Command-line arguments take
+/// precedence over these.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// Base URL of xkcd or a mirror of it.
+    pub base_url: String,
+    /// Scheme and host to download images from instead of the one in each
+    /// comic's `img` URL, such as `http://localhost:8080`.
+    pub img_host: Option<String>,
+    /// Maximum number of HTTP requests per second, or zero for no limit.
+    pub requests_per_second: f64,
+    /// `User-Agent` header to send instead of the default one.
+    pub user_agent: Option<String>,
+    /// Number of backups of the data store to keep, or zero for none.
+    pub backups: usize,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            base_url: "https://xkcd.com".to_owned(),
+            img_host: None,
+            requests_per_second: 5.0,
+            user_agent: None,
+            backups: crate::backup::DEFAULT_BACKUPS_TO_KEEP,
+        }
+    }
+}
+impl Config {
+    /// Loads the config file, or returns the default config if there isn't
+    /// one.
+    pub fn load() -> Result<Self> {
+        let path = crate::util::config_path();
+        let config: Self = match std::fs::read_to_string(&path) {
+            Ok(json_string) => serde_json::from_str(&json_string)
+                .wrap_err_with(|| format!("error parsing {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).wrap_err_with(|| format!("error reading {}", path.display())),
+        };
+        config
+            .check()
+            .wrap_err_with(|| format!("error in {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Returns an error if any setting has a value that can't be used.
+    fn check(&self) -> Result<()> {
+        crate::fetch::request_interval(self.requests_per_second)
+            .map_err(|e| eyre!("requests_per_second: {e}"))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,441 @@
+use std::path::Path;
+
+use eyre::{bail, ensure, Result, WrapErr};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OpenFlags};
+
+use crate::fetch::Validators;
+use crate::store::{Comic, Store};
+
+/// Steps that upgrade the database from each schema version to the next. The
+/// schema version is kept in `PRAGMA user_version`, and databases created
+/// before it was set are version 0.
+const MIGRATIONS: &[&str] = &[
+    // 1: Comics, image validators, tiers, and tier assignments. The tables
+    // might already exist in databases from before versioning.
+    "
+    CREATE TABLE IF NOT EXISTS comics (
+        num INTEGER PRIMARY KEY,
+        year TEXT NOT NULL,
+        month TEXT NOT NULL,
+        day TEXT NOT NULL,
+        title TEXT NOT NULL,
+        safe_title TEXT NOT NULL,
+        img TEXT NOT NULL,
+        alt TEXT NOT NULL,
+        link TEXT NOT NULL,
+        news TEXT NOT NULL,
+        transcript TEXT NOT NULL,
+        -- Every other field, as a JSON object.
+        extra TEXT,
+        -- HTTP cache validators for the metadata.
+        etag TEXT,
+        last_modified TEXT
+    );
+    -- HTTP cache validators for downloaded images.
+    CREATE TABLE IF NOT EXISTS images (
+        num INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        etag TEXT,
+        last_modified TEXT,
+        PRIMARY KEY (num, kind)
+    );
+    CREATE TABLE IF NOT EXISTS tiers (
+        id INTEGER PRIMARY KEY,
+        title TEXT NOT NULL,
+        subtitle TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS tier_assignments (
+        num INTEGER PRIMARY KEY,
+        tier INTEGER NOT NULL
+    );
+    ",
+    // 2: Index for finding the comics in a tier.
+    "CREATE INDEX IF NOT EXISTS tier_assignments_by_tier ON tier_assignments (tier);",
+];
+
+/// Schema version that this version of the program reads and writes.
+const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+/// Fields of [`Comic`] that have their own column in the `comics` table.
+const COMIC_COLUMNS: &[&str] = &[
+    "year",
+    "month",
+    "day",
+    "title",
+    "safe_title",
+    "img",
+    "alt",
+    "link",
+    "news",
+    "transcript",
+];
+
+/// Opens the database at `path`, creating it if it doesn't exist and
+/// upgrading it if it has an older schema.
+pub fn open(path: &Path) -> Result<Connection> {
+    let mut conn =
+        Connection::open(path).wrap_err_with(|| format!("error opening {}", path.display()))?;
+    migrate(&mut conn).wrap_err_with(|| format!("error opening {}", path.display()))?;
+    Ok(conn)
+}
+
+/// Upgrades the database to [`SCHEMA_VERSION`], one step at a time.
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > SCHEMA_VERSION {
+        bail!(
+            "database has schema version {version}, but this version of xkcd-rank only \
+             supports up to {SCHEMA_VERSION}; please use a newer version",
+        );
+    }
+    for (i, step) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(step)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Opens the database at `path` without changing it, even to upgrade it.
+pub fn open_read_only(path: &Path) -> Result<Connection> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .wrap_err_with(|| format!("error opening {}", path.display()))?;
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    ensure!(
+        version <= SCHEMA_VERSION,
+        "database has unsupported schema version {version}",
+    );
+    Ok(conn)
+}
+
+/// Checks that the database at `path` can be read, without changing it.
+pub fn check(path: &Path) -> Result<()> {
+    read_store(&open_read_only(path)?)?;
+    Ok(())
+}
+
+/// Reads the whole store from the database.
+pub fn read_store(conn: &Connection) -> Result<Store> {
+    let mut store = Store::default();
+
+    let columns = COMIC_COLUMNS.join(", ");
+    let mut stmt = conn.prepare(&format!(
+        "SELECT num, {columns}, extra, etag, last_modified FROM comics"
+    ))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let num: usize = row.get("num")?;
+        let mut fields = match row.get::<_, Option<String>>("extra")? {
+            Some(extra) => serde_json::from_str(&extra)
+                .wrap_err_with(|| format!("error parsing extra fields of comic #{num}"))?,
+            None => serde_json::Map::new(),
+        };
+        fields.insert("num".to_owned(), num.into());
+        for &column in COMIC_COLUMNS {
+            fields.insert(column.to_owned(), row.get::<_, String>(column)?.into());
+        }
+        let comic: Comic = serde_json::from_value(fields.into())
+            .wrap_err_with(|| format!("error reading comic #{num}"))?;
+        store.insert_comic(comic);
+        let metadata = Validators {
+            etag: row.get("etag")?,
+            last_modified: row.get("last_modified")?,
+        };
+        store.update_validators(num, |v| v.metadata = metadata);
+    }
+
+    let mut stmt = conn.prepare("SELECT num, kind, etag, last_modified FROM images")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let num: usize = row.get("num")?;
+        let validators = Validators {
+            etag: row.get("etag")?,
+            last_modified: row.get("last_modified")?,
+        };
+        match row.get::<_, String>("kind")?.as_str() {
+            "image" => store.update_validators(num, |v| v.image = validators),
+            "large_image" => store.update_validators(num, |v| v.large_image = validators),
+            kind => eprintln!("unknown image kind {kind:?} for comic #{num}"),
+        }
+    }
+
+    let mut stmt = conn.prepare("SELECT num, tier FROM tier_assignments")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let num: usize = row.get("num")?;
+        if store.tier_assignments.len() <= num {
+            store.tier_assignments.resize(num + 1, 0);
+        }
+        store.tier_assignments[num] = row.get("tier")?;
+    }
+
+    // Reading isn't a change.
+    store.mark_saved();
+    Ok(store)
+}
+
+/// Writes everything about the comics in `nums` to the database, replacing
+/// what was there before.
+pub fn write_comics(
+    conn: &mut Connection,
+    store: &Store,
+    nums: impl IntoIterator<Item = usize>,
+) -> Result<()> {
+    let tx = conn.transaction()?;
+
+    for (id, tier) in crate::TIERS.iter().enumerate() {
+        tx.execute(
+            "INSERT OR REPLACE INTO tiers (id, title, subtitle) VALUES (?1, ?2, ?3)",
+            params![id, tier.title, tier.subtitle],
+        )?;
+    }
+
+    for num in nums {
+        tx.execute("DELETE FROM comics WHERE num = ?1", [num])?;
+        tx.execute("DELETE FROM images WHERE num = ?1", [num])?;
+        tx.execute("DELETE FROM tier_assignments WHERE num = ?1", [num])?;
+
+        let validators = store.get_validators(num);
+
+        if let Some(comic) = store.get_comic(num) {
+            let serde_json::Value::Object(mut extra) = serde_json::to_value(comic)? else {
+                unreachable!("comics should serialize to objects");
+            };
+            extra.remove("num");
+            let columns: Vec<String> = COMIC_COLUMNS
+                .iter()
+                .map(|&column| match extra.remove(column) {
+                    Some(serde_json::Value::String(s)) => s,
+                    _ => String::new(),
+                })
+                .collect();
+            let extra = (!extra.is_empty())
+                .then(|| serde_json::to_string(&extra))
+                .transpose()?;
+
+            let mut values: Vec<Value> = vec![Value::Integer(num as i64)];
+            values.extend(columns.into_iter().map(Value::from));
+            values.extend([
+                extra.into(),
+                validators.metadata.etag.clone().into(),
+                validators.metadata.last_modified.clone().into(),
+            ]);
+            let placeholders = vec!["?"; values.len()].join(", ");
+            tx.execute(
+                &format!(
+                    "INSERT INTO comics (num, {}, extra, etag, last_modified) \
+                     VALUES ({placeholders})",
+                    COMIC_COLUMNS.join(", "),
+                ),
+                params_from_iter(values),
+            )?;
+        }
+
+        for (kind, v) in [
+            ("image", &validators.image),
+            ("large_image", &validators.large_image),
+        ] {
+            if *v != Validators::default() {
+                tx.execute(
+                    "INSERT INTO images (num, kind, etag, last_modified) VALUES (?1, ?2, ?3, ?4)",
+                    params![num, kind, v.etag, v.last_modified],
+                )?;
+            }
+        }
+
+        let tier = store.get_tier_of_comic(num);
+        if tier != 0 {
+            tx.execute(
+                "INSERT INTO tier_assignments (num, tier) VALUES (?1, ?2)",
+                params![num, tier],
+            )?;
+        }
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// Copies everything from the JSON store at `json_path` into a new database at
+/// `db_path`. The JSON file is renamed to `migrated_path` afterwards so that
+/// this only happens once.
+pub fn migrate_from_json(json_path: &Path, db_path: &Path, migrated_path: &Path) -> Result<()> {
+    let json_string = std::fs::read_to_string(json_path)
+        .wrap_err_with(|| format!("error reading {}", json_path.display()))?;
+    let store: Store = serde_json::from_str(&json_string)
+        .wrap_err_with(|| format!("error parsing {}", json_path.display()))?;
+
+    // Write to a temporary file first so that a failed migration doesn't
+    // leave behind a database that would be used instead of the JSON.
+    let tmp_path = db_path.with_extension("sqlite.tmp");
+    if tmp_path.exists() {
+        std::fs::remove_file(&tmp_path)?;
+    }
+    let mut conn = open(&tmp_path)?;
+    let len = store
+        .comics
+        .len()
+        .max(store.tier_assignments.len())
+        .max(store.validators.len());
+    write_comics(&mut conn, &store, 0..len)?;
+    drop(conn);
+    std::fs::rename(&tmp_path, db_path)?;
+
+    std::fs::rename(json_path, migrated_path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::store::ComicValidators;
+
+    /// Directory that is deleted when dropped.
+    struct TempDir(PathBuf);
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("xkcd-rank-test-{}-{name}", std::process::id(),));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const JSON_STORE: &str = r#"{
+        "comics": [
+            null,
+            {
+                "num": 1, "year": "2006", "month": "1", "day": "1",
+                "title": "Barrel - Part 1", "safe_title": "Barrel - Part 1",
+                "img": "https://imgs.xkcd.com/comics/barrel_cropped_(1).jpg",
+                "alt": "Don't we all.", "link": "", "news": "", "transcript": "",
+                "future_field": { "nested": [1, 2] }
+            },
+            null,
+            {
+                "num": 3, "year": "2006", "month": "1", "day": "1",
+                "title": "Café", "safe_title": "Café", "img": "", "alt": "",
+                "link": "", "news": "", "transcript": "",
+                "extra_parts": { "pre": "<div>", "post": "</div>" },
+                "raw_title": "CafÃ©", "raw_safe_title": "CafÃ©"
+            }
+        ],
+        "tier_assignments": [0, 2, 0, 7],
+        "validators": [
+            {},
+            {
+                "metadata": { "etag": "\"m1\"" },
+                "image": { "etag": "\"i1\"", "last_modified": "Sun, 01 Jan 2006 00:00:00 GMT" }
+            },
+            {},
+            { "large_image": { "last_modified": "Mon, 02 Jan 2006 00:00:00 GMT" } }
+        ]
+    }"#;
+
+    /// Migrates [`JSON_STORE`] into a database in `dir` and returns its path.
+    fn migrate(dir: &TempDir) -> PathBuf {
+        let json_path = dir.0.join("comics.json");
+        let db_path = dir.0.join("comics.sqlite");
+        let migrated_path = dir.0.join("comics.json.migrated");
+        std::fs::write(&json_path, JSON_STORE).unwrap();
+        migrate_from_json(&json_path, &db_path, &migrated_path).unwrap();
+        assert!(!json_path.exists());
+        assert!(migrated_path.exists());
+        db_path
+    }
+
+    #[test]
+    fn json_store_round_trips() {
+        let dir = TempDir::new("db-round-trip");
+        let db_path = migrate(&dir);
+        let expected: Store = serde_json::from_str(JSON_STORE).unwrap();
+
+        let store = read_store(&open(&db_path).unwrap()).unwrap();
+
+        assert_eq!(store.comics, expected.comics);
+        assert_eq!(
+            store.get_comic(1).unwrap().extra["future_field"],
+            serde_json::json!({ "nested": [1, 2] }),
+        );
+        assert!(store.get_comic(3).unwrap().extra_parts.is_some());
+        assert_eq!(
+            store.get_comic(3).unwrap().raw_title.as_deref(),
+            Some("CafÃ©")
+        );
+        for n in 0..=4 {
+            assert_eq!(store.get_tier_of_comic(n), expected.get_tier_of_comic(n));
+            assert_eq!(store.get_validators(n), expected.get_validators(n));
+        }
+        assert_ne!(store.get_validators(1), ComicValidators::default());
+    }
+
+    #[test]
+    fn resetting_tier_deletes_row() {
+        let dir = TempDir::new("db-reset-tier");
+        let db_path = migrate(&dir);
+        let mut conn = open(&db_path).unwrap();
+        let mut store = read_store(&conn).unwrap();
+
+        store.set_tier_of_comic(1, 0);
+        write_comics(&mut conn, &store, [1]).unwrap();
+
+        let rows: usize = conn
+            .query_row(
+                "SELECT COUNT(*) FROM tier_assignments WHERE num = 1",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(rows, 0);
+        let store = read_store(&conn).unwrap();
+        assert_eq!(store.get_tier_of_comic(1), 0);
+        assert_eq!(store.get_tier_of_comic(3), 7);
+    }
+
+    #[test]
+    fn old_database_is_upgraded() {
+        let dir = TempDir::new("db-upgrade");
+        let db_path = dir.0.join("comics.sqlite");
+        Connection::open(&db_path).unwrap();
+
+        let conn = open(&db_path).unwrap();
+
+        let version: usize = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+        read_store(&conn).unwrap();
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        let dir = TempDir::new("db-newer");
+        let db_path = dir.0.join("comics.sqlite");
+        let conn = open(&db_path).unwrap();
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        drop(conn);
+
+        let e = open(&db_path).unwrap_err();
+        assert!(format!("{e:#}").contains("schema version"), "{e:#}");
+        assert!(open_read_only(&db_path).is_err());
+        assert!(check(&db_path).is_err());
+        // Refusing must not downgrade it.
+        let version: usize = Connection::open(&db_path)
+            .unwrap()
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION + 1);
+    }
+}
@@ -1,54 +1,564 @@
-use std::io::Write;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex, Once};
+use std::time::{Duration, Instant};
 
+use serde::Serialize;
+
+use crate::date::DateRange;
+use crate::fetch::{Client, ErrorKind, FetchError, Fetched, RetryPolicy};
+use crate::progress::{Event, Progress, ProgressFormat, Stage};
+use crate::select::Selection;
 use crate::store::*;
 
-pub fn download_all_comics(redownload: bool) -> eyre::Result<()> {
-    println!("Fetching latest comic ...");
-    let latest_comic = Comic::get_from_url("https://xkcd.com/info.0.json")?;
+/// Comic to fetch, along with whatever we already know about it.
+struct Job {
+    n: usize,
+    comic: Option<Comic>,
+    validators: ComicValidators,
+}
 
-    let count = latest_comic.num;
+/// Outcome of fetching a single comic.
+struct JobResult {
+    n: usize,
+    /// Metadata for the comic and how long it took to fetch, or `None` if we
+    /// already had the metadata.
+    metadata: Option<Result<(Fetched<Comic>, Duration), FetchError>>,
+    /// Size of the image and how long it took to download, or `None` if the
+    /// image did not need to be downloaded.
+    image: Option<Result<(Fetched<u64>, Duration), FetchError>>,
+    /// Same as `image`, but for the bigger version of the image.
+    large_image: Option<Result<(Fetched<u64>, Duration), FetchError>>,
+}
 
-    println!("There are {count} comics (excluding 404)");
+/// Options for a sync run.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// What to download again even if we already have it.
+    pub refresh: Refresh,
+    /// Check every comic instead of only the ones newer than the newest comic
+    /// in the store.
+    pub full: bool,
+    /// Number of comics to download in parallel.
+    pub jobs: usize,
+    /// How to retry after transient errors.
+    pub retry: RetryPolicy,
+    /// How to report progress.
+    pub progress: ProgressFormat,
+    /// Number of the latest comic, or `None` to ask xkcd.
+    pub count: Option<usize>,
+    /// Print what would be downloaded without downloading anything.
+    pub dry_run: bool,
+    /// Only download these comics, instead of all of them.
+    pub only: Option<Selection>,
+    /// Only download images for comics published in this range.
+    pub dates: DateRange,
+    /// Save the store after this many comics.
+    pub checkpoint_every: Option<usize>,
+    /// Save the store after this much time.
+    pub checkpoint_interval: Option<Duration>,
+}
 
-    let mut store = if redownload {
-        Store::default()
+/// What to download again even if we already have it. Tier assignments are
+/// always kept.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Refresh {
+    /// Replace the metadata of comics in the store.
+    pub metadata: bool,
+    /// Replace downloaded images.
+    pub images: bool,
+}
+impl Refresh {
+    pub fn any(self) -> bool {
+        self.metadata || self.images
+    }
+}
+
+pub fn download_all_comics(
+    store: &mut Store,
+    client: &Client,
+    opts: DownloadOptions,
+) -> eyre::Result<()> {
+    let DownloadOptions {
+        refresh,
+        full,
+        retry,
+        count,
+        dry_run,
+        dates,
+        ..
+    } = opts;
+    let progress = Progress::new(opts.progress);
+
+    // `nums` is what we will download, and `all` is what we would download
+    // with `--redownload`.
+    let (nums, all): (Vec<usize>, Vec<usize>) = if let Some(only) = &opts.only {
+        let nums = only.comics(store);
+        (nums.clone(), nums)
     } else {
-        Store::load()
+        let count = match count {
+            Some(count) => count,
+            None => {
+                progress.info("Fetching latest comic ...");
+                retry
+                    .run(|| Comic::get_from_url(client, &client.latest_comic_url()))?
+                    .num
+            }
+        };
+
+        progress.info(format!("There are {count} comics (excluding 404)"));
+
+        // We don't know when comics we haven't fetched were published, so
+        // filtering by date has to look at all of them.
+        let nums = if full || refresh.any() || !dates.is_unbounded() {
+            (1..=count).collect()
+        } else {
+            let newest = store.latest_comic_num();
+            progress.info(format!("Newest comic in store is #{newest}"));
+            // Jobs finish out of order, so comics that failed or were
+            // interrupted last time can be anywhere below the newest one.
+            let unfinished = (1..=newest.min(count)).filter(|&n| {
+                store
+                    .get_comic(n)
+                    .is_none_or(|comic| !comic.has_all_images_downloaded())
+            });
+            unfinished.chain(newest + 1..=count).collect()
+        };
+
+        (nums, (1..=count).collect())
     };
 
-    for i in 1..=count {
-        if store.has_comic(i)
-            && store
-                .fetch_comic(i)
-                .is_ok_and(|comic| comic.has_image_downloaded())
-        {
+    // Comics we don't have yet stay in, since we need their metadata to know
+    // when they were published.
+    let in_range = |n: &usize| {
+        store
+            .get_comic(*n)
+            .is_none_or(|comic| comic.is_published_in(dates))
+    };
+    let (nums, all): (Vec<usize>, Vec<usize>) = (
+        nums.into_iter().filter(in_range).collect(),
+        all.into_iter().filter(in_range).collect(),
+    );
+
+    if dry_run {
+        let plan = Plan::new(store, &nums, &all, refresh);
+        progress.emit(Event::Planned {
+            metadata: &plan.metadata,
+            images: &plan.images,
+            redownload: &plan.redownload,
+        });
+        return Ok(());
+    }
+
+    remove_stale_partial_downloads(store);
+
+    let summary = download_comics(store, client, nums, &opts, &progress);
+    progress.finished(&summary);
+
+    store.save();
+
+    Ok(())
+}
+
+/// Fetches metadata and images for the comics in `nums`, skipping ones that we
+/// already have both for unless `opts.refresh` says otherwise.
+pub fn download_comics(
+    store: &mut Store,
+    client: &Client,
+    nums: impl IntoIterator<Item = usize>,
+    opts: &DownloadOptions,
+    progress: &Progress,
+) -> Summary {
+    let &DownloadOptions {
+        refresh,
+        jobs,
+        retry,
+        dates,
+        checkpoint_every,
+        checkpoint_interval,
+        ..
+    } = opts;
+
+    install_interrupt_handler();
+
+    let queue: Vec<Job> = nums
+        .into_iter()
+        .filter_map(|n| {
+            let comic = store.get_comic(n).cloned();
+            if !refresh.any()
+                && comic
+                    .as_ref()
+                    .is_some_and(|c| c.has_all_images_downloaded())
+            {
+                return None;
+            }
+            let validators = store.get_validators(n);
+            Some(Job {
+                n,
+                comic,
+                validators,
+            })
+        })
+        .collect();
+
+    progress.emit(Event::Started {
+        queued: queue.len(),
+        jobs,
+    });
+
+    let mut summary = Summary::default();
+    let queue = Mutex::new(queue.into_iter());
+    let (tx, rx) = mpsc::channel();
+
+    std::thread::scope(|s| {
+        for _ in 0..jobs.max(1) {
+            let tx = tx.clone();
+            let queue = &queue;
+            s.spawn(move || loop {
+                if INTERRUPTED.load(Ordering::Relaxed) {
+                    break;
+                }
+                let Some(job) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                if tx
+                    .send(run_job(client, job, refresh, dates, &retry))
+                    .is_err()
+                {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        // Only this thread touches the store or prints, so output from
+        // different comics never gets interleaved.
+        let mut last_checkpoint = Instant::now();
+        for (i, result) in rx.into_iter().enumerate() {
+            summary.record(store, progress, result);
+            let done = i + 1;
+
+            let due_by_count = checkpoint_every.is_some_and(|every| done % every == 0);
+            let due_by_time =
+                checkpoint_interval.is_some_and(|interval| last_checkpoint.elapsed() >= interval);
+            if due_by_count || due_by_time {
+                store.save();
+                last_checkpoint = Instant::now();
+                progress.emit(Event::Checkpoint { done });
+            }
+        }
+    });
+
+    summary.interrupted = INTERRUPTED.load(Ordering::Relaxed);
+    summary.added.sort();
+    for comics in summary.failed.values_mut() {
+        comics.sort();
+    }
+
+    summary
+}
+
+/// Set when the user presses Ctrl+C, so that we stop starting new jobs.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Makes Ctrl+C stop the download after the comics in progress are done. A
+/// second Ctrl+C exits immediately.
+fn install_interrupt_handler() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let result = ctrlc::set_handler(|| {
+            if INTERRUPTED.swap(true, Ordering::Relaxed) {
+                std::process::exit(130);
+            }
+            eprintln!("Interrupted; finishing comics in progress. Press Ctrl+C again to quit now.");
+        });
+        if let Err(e) = result {
+            eprintln!("error installing Ctrl+C handler: {e}");
+        }
+    });
+}
+
+/// Removes `.part` files left over from interrupted image downloads, except
+/// ones that might still be resumed.
+fn remove_stale_partial_downloads(store: &Store) {
+    let Ok(entries) = std::fs::read_dir(crate::util::img_dir()) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "part") {
+            // Validators are removed along with their partial download.
             continue;
         }
+        let file_name = entry.file_name();
+        let comic = file_name
+            .to_string_lossy()
+            .split_once(['.', '-'])
+            .and_then(|(n, _)| n.parse().ok())
+            .and_then(|n| store.get_comic(n));
+        if comic.is_none_or(|comic| comic.has_all_images_downloaded()) {
+            if let Err(e) = crate::fetch::remove_partial_download(&path) {
+                eprintln!("error removing {}: {e}", path.display());
+            }
+        }
+    }
+}
 
-        println!("Fetching comic #{i} ...");
-        match store.fetch_comic(i) {
-            Ok(comic) => {
-                if redownload || !comic.has_image_downloaded() {
-                    print!("Downloading image #{i} ...");
-                    let _ = std::io::stdout().flush();
-                    match comic.download_image() {
-                        Ok(()) => println!(" done!"),
-                        Err(e) => println!(" error: {e}"),
-                    }
+/// What a sync run would download.
+struct Plan {
+    /// Comics whose metadata would be fetched.
+    metadata: Vec<usize>,
+    /// Comics whose image would be downloaded.
+    images: Vec<usize>,
+    /// Comics that we already have that would be downloaded again with
+    /// `--redownload`.
+    redownload: Vec<usize>,
+}
+impl Plan {
+    fn new(store: &Store, nums: &[usize], all: &[usize], refresh: Refresh) -> Self {
+        let mut metadata = vec![];
+        let mut images = vec![];
+        for &n in nums {
+            let comic = store.get_comic(n);
+            if refresh.metadata || comic.is_none() {
+                metadata.push(n);
+            }
+            if refresh.images || !comic.is_some_and(|c| c.has_all_images_downloaded()) {
+                images.push(n);
+            }
+        }
+        let redownload = all
+            .iter()
+            .copied()
+            .filter(|&n| store.get_comic(n).is_some())
+            .collect();
+        Self {
+            metadata,
+            images,
+            redownload,
+        }
+    }
+}
+
+/// Formats a sorted list of comic numbers compactly, such as `#1-#3, #7`.
+pub fn format_comic_list(nums: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for &n in nums {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == n => *end = n,
+            _ => ranges.push((n, n)),
+        }
+    }
+    let strings: Vec<String> = ranges
+        .into_iter()
+        .map(|(start, end)| match start == end {
+            true => format!("#{start}"),
+            false => format!("#{start}-#{end}"),
+        })
+        .collect();
+    strings.join(", ")
+}
+
+/// What a sync run changed.
+#[derive(Serialize, Debug, Default)]
+pub struct Summary {
+    /// Comics whose metadata was not in the store before.
+    added: Vec<usize>,
+    /// Number of images downloaded, including large ones.
+    images: usize,
+    /// Number of metadata and image requests that found nothing had changed.
+    unchanged: usize,
+    /// Comics that had an error, grouped by the kind of error.
+    failed: BTreeMap<ErrorKind, Vec<usize>>,
+    /// Whether the user pressed Ctrl+C before we were done.
+    interrupted: bool,
+}
+impl Summary {
+    /// Records the result of a job in the store and in the summary.
+    fn record(&mut self, store: &mut Store, progress: &Progress, result: JobResult) {
+        let n = result.n;
+        match result.metadata {
+            Some(Ok((Fetched::Modified(comic, validators), duration))) => {
+                if store.get_comic(n).is_none() {
+                    self.added.push(n);
                 }
+                store.insert_comic(comic);
+                store.update_validators(n, |v| v.metadata = validators);
+                progress.emit(Event::MetadataFetched {
+                    comic: n,
+                    millis: duration.as_millis(),
+                });
+            }
+            Some(Ok((Fetched::NotModified, _))) => {
+                self.unchanged += 1;
+                progress.emit(Event::Unchanged {
+                    comic: n,
+                    stage: Stage::Metadata,
+                });
+            }
+            Some(Err(e)) => {
+                self.fail(n, e.kind);
+                progress.error(n, Stage::Metadata, &e);
+                return;
+            }
+            None => (),
+        }
+
+        match result.image {
+            Some(Ok((Fetched::Modified(bytes, validators), duration))) => {
+                self.images += 1;
+                store.update_validators(n, |v| v.image = validators);
+                progress.emit(Event::ImageDownloaded {
+                    comic: n,
+                    bytes,
+                    millis: duration.as_millis(),
+                });
+            }
+            Some(Ok((Fetched::NotModified, _))) => {
+                self.unchanged += 1;
+                progress.emit(Event::Unchanged {
+                    comic: n,
+                    stage: Stage::Image,
+                });
+            }
+            Some(Err(e)) => {
+                self.fail(n, e.kind);
+                progress.error(n, Stage::Image, &e);
             }
-            Err(e) => eprintln!("error fetching comic #{i}: {e}"),
+            None => (),
         }
 
-        // if i % 10 == 0 {
-        //     println!("Saving database backup ...");
-        //     store.save();
-        // }
+        match result.large_image {
+            Some(Ok((Fetched::Modified(bytes, validators), duration))) => {
+                self.images += 1;
+                store.update_validators(n, |v| v.large_image = validators);
+                progress.emit(Event::LargeImageDownloaded {
+                    comic: n,
+                    bytes,
+                    millis: duration.as_millis(),
+                });
+            }
+            Some(Ok((Fetched::NotModified, _))) => {
+                self.unchanged += 1;
+                progress.emit(Event::Unchanged {
+                    comic: n,
+                    stage: Stage::LargeImage,
+                });
+            }
+            Some(Err(e)) => {
+                self.fail(n, e.kind);
+                progress.error(n, Stage::LargeImage, &e);
+            }
+            None => (),
+        }
     }
 
-    println!("Done fetching all comics!");
+    fn fail(&mut self, n: usize, kind: ErrorKind) {
+        self.failed.entry(kind).or_default().push(n);
+    }
 
-    store.save();
+    pub fn print(&self) {
+        if self.interrupted {
+            println!("Interrupted before all comics were downloaded");
+        } else {
+            println!("Done fetching all comics!");
+        }
+        match self.added.as_slice() {
+            [] => println!("No new comics"),
+            [n] => println!("Added comic #{n}"),
+            added => {
+                let list = format_comic_list(added);
+                println!("Added {} comics: {list}", added.len());
+            }
+        }
+        println!("Downloaded {} images", self.images);
+        if self.unchanged > 0 {
+            println!("{} were unchanged", self.unchanged);
+        }
+        for (kind, comics) in &self.failed {
+            let list = format_comic_list(comics);
+            println!("{} failed ({kind}): {list}", comics.len());
+        }
+    }
+}
 
-    Ok(())
+fn run_job(
+    client: &Client,
+    job: Job,
+    refresh: Refresh,
+    dates: DateRange,
+    retry: &RetryPolicy,
+) -> JobResult {
+    let n = job.n;
+    let mut metadata = None;
+    let mut comic = job.comic;
+
+    if refresh.metadata || comic.is_none() {
+        let start = Instant::now();
+        // Validators only make sense if we still have the comic they are for.
+        let validators = match comic {
+            Some(_) => job.validators.metadata.clone(),
+            None => Default::default(),
+        };
+        match retry.run(|| Comic::get_nth_if_modified(client, n, &validators)) {
+            Ok(fetched) => {
+                if let Fetched::Modified(new_comic, _) = &fetched {
+                    comic = Some(new_comic.clone());
+                }
+                metadata = Some(Ok((fetched, start.elapsed())));
+            }
+            Err(e) => {
+                return JobResult {
+                    n,
+                    metadata: Some(Err(e)),
+                    image: None,
+                    large_image: None,
+                };
+            }
+        }
+    }
+
+    // We didn't send validators, so a 304 here means the server is broken.
+    let Some(comic) = comic else {
+        return JobResult {
+            n,
+            metadata: Some(Err(FetchError::new(
+                ErrorKind::ClientError,
+                "unexpected 304 response",
+            ))),
+            image: None,
+            large_image: None,
+        };
+    };
+    if !comic.is_published_in(dates) {
+        return JobResult {
+            n,
+            metadata,
+            image: None,
+            large_image: None,
+        };
+    }
+
+    // Some interactive comics have no image at all.
+    let needs_image = comic.has_image() && (refresh.images || !comic.has_image_downloaded());
+    let image = needs_image.then(|| {
+        let start = Instant::now();
+        let fetched = comic.download_image(client, retry, &job.validators.image)?;
+        Ok((fetched, start.elapsed()))
+    });
+
+    let needs_large_image =
+        comic.large_img_url().is_some() && (refresh.images || !comic.has_large_image_downloaded());
+    let large_image = needs_large_image.then(|| {
+        let start = Instant::now();
+        let fetched = comic.download_large_image(client, retry, &job.validators.large_image)?;
+        Ok((fetched, start.elapsed()))
+    });
+
+    JobResult {
+        n,
+        metadata,
+        image,
+        large_image,
+    }
 }
@@ -0,0 +1,587 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::fetcher::{Fetcher, Request, Response, UreqFetcher};
+
+/// Kind of error that can happen while fetching something from xkcd.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The server took too long to respond.
+    Timeout,
+    /// Connection error, DNS failure, etc.
+    Network,
+    /// HTTP 404.
+    NotFound,
+    /// HTTP 429.
+    RateLimited,
+    /// HTTP 5xx.
+    ServerError,
+    /// Any other HTTP error status.
+    ClientError,
+    /// The response could not be parsed.
+    Decode,
+    /// Error reading or writing a local file.
+    Disk,
+}
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Timeout => write!(f, "timeout"),
+            ErrorKind::Network => write!(f, "network error"),
+            ErrorKind::NotFound => write!(f, "not found"),
+            ErrorKind::RateLimited => write!(f, "rate limited"),
+            ErrorKind::ServerError => write!(f, "server error"),
+            ErrorKind::ClientError => write!(f, "client error"),
+            ErrorKind::Decode => write!(f, "decode error"),
+            ErrorKind::Disk => write!(f, "disk error"),
+        }
+    }
+}
+impl ErrorKind {
+    /// Returns the kind of error for an HTTP error status.
+    fn from_status(status: u16) -> Self {
+        match status {
+            404 => ErrorKind::NotFound,
+            429 => ErrorKind::RateLimited,
+            500..=599 => ErrorKind::ServerError,
+            _ => ErrorKind::ClientError,
+        }
+    }
+
+    /// Returns whether trying again later might succeed.
+    pub fn is_transient(self) -> bool {
+        match self {
+            ErrorKind::Timeout
+            | ErrorKind::Network
+            | ErrorKind::RateLimited
+            | ErrorKind::ServerError => true,
+            ErrorKind::NotFound | ErrorKind::ClientError | ErrorKind::Decode | ErrorKind::Disk => {
+                false
+            }
+        }
+    }
+}
+
+/// Error fetching something from xkcd, classified by [`ErrorKind`].
+#[derive(Debug)]
+pub struct FetchError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)
+    }
+}
+impl std::error::Error for FetchError {}
+impl FetchError {
+    pub fn new(kind: ErrorKind, message: impl fmt::Display) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn disk(e: std::io::Error) -> Self {
+        Self::new(ErrorKind::Disk, e)
+    }
+
+    fn status(url: &str, status: u16) -> Self {
+        Self::new(
+            ErrorKind::from_status(status),
+            format!("{url}: status code {status}"),
+        )
+    }
+
+    /// Returns an error for failing to read a response body.
+    pub fn body(e: std::io::Error) -> Self {
+        Self::new(io_error_kind(&e), e)
+    }
+}
+impl From<ureq::Error> for FetchError {
+    fn from(e: ureq::Error) -> Self {
+        let kind = match &e {
+            ureq::Error::Status(status, _) => ErrorKind::from_status(*status),
+            ureq::Error::Transport(t) => match t.kind() {
+                ureq::ErrorKind::InvalidUrl | ureq::ErrorKind::UnknownScheme => {
+                    ErrorKind::ClientError
+                }
+                _ => match std::error::Error::source(t).and_then(|e| e.downcast_ref()) {
+                    Some(io_error) => io_error_kind(io_error),
+                    None => ErrorKind::Network,
+                },
+            },
+        };
+        Self::new(kind, e)
+    }
+}
+
+fn io_error_kind(e: &std::io::Error) -> ErrorKind {
+    match e.kind() {
+        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => ErrorKind::Timeout,
+        _ => ErrorKind::Network,
+    }
+}
+
+/// Default `User-Agent` header, so that server operators know who we are.
+pub const DEFAULT_USER_AGENT: &str = concat!(
+    "xkcd-rank/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://github.com/HactarCE/xkcd-rank)",
+);
+
+/// HTTP client for xkcd or a mirror of it.
+#[derive(Debug, Clone)]
+pub struct Client {
+    fetcher: Arc<dyn Fetcher>,
+    /// Shared between clones so that parallel jobs share one limit.
+    limiter: Arc<RateLimiter>,
+    /// Base URL of the site, without a trailing slash.
+    base_url: String,
+    /// Scheme and host to download images from instead of the one in each
+    /// comic's `img` URL.
+    img_host: Option<String>,
+}
+impl Client {
+    /// Constructs a client that uses the network.
+    pub fn new(config: &Config) -> Self {
+        let user_agent = config.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT);
+        Self::with_fetcher(config, Arc::new(UreqFetcher::new(user_agent)))
+    }
+
+    /// Constructs a client that sends requests using `fetcher`.
+    pub fn with_fetcher(config: &Config, fetcher: Arc<dyn Fetcher>) -> Self {
+        Self {
+            fetcher,
+            limiter: Arc::new(RateLimiter::new(config.requests_per_second)),
+            base_url: config.base_url.trim_end_matches('/').to_owned(),
+            img_host: config
+                .img_host
+                .as_ref()
+                .map(|host| host.trim_end_matches('/').to_owned()),
+        }
+    }
+
+    /// Returns the URL of the metadata for the latest comic.
+    pub fn latest_comic_url(&self) -> String {
+        format!("{}/info.0.json", self.base_url)
+    }
+
+    /// Returns the URL of the metadata for comic `n`.
+    pub fn comic_url(&self, n: usize) -> String {
+        format!("{}/{n}/info.0.json", self.base_url)
+    }
+
+    /// Returns the URL of the web page for comic `n`.
+    pub fn comic_page_url(&self, n: usize) -> String {
+        format!("{}/{n}/", self.base_url)
+    }
+
+    /// Returns `url` rewritten to point at the configured image host, if
+    /// there is one.
+    pub fn img_url(&self, url: &str) -> String {
+        let Some(img_host) = &self.img_host else {
+            return url.to_owned();
+        };
+        let path_start = url
+            .find("://")
+            .and_then(|i| url[i + 3..].find('/').map(|j| i + 3 + j))
+            .unwrap_or(url.len());
+        format!("{img_host}{}", &url[path_start..])
+    }
+
+    /// Sends `request` once the rate limit allows it.
+    ///
+    /// If the server asks us to slow down using `Retry-After`, all requests
+    /// from this client wait that long.
+    fn call(&self, request: &Request) -> Result<Response, FetchError> {
+        self.limiter.wait();
+        let response = self.fetcher.get(request)?;
+        if matches!(response.status, 429 | 503) {
+            if let Some(delay) = response.header("Retry-After").and_then(parse_retry_after) {
+                self.limiter.pause(delay);
+            }
+        }
+        Ok(response)
+    }
+
+    /// Fetches `url` unless it hasn't changed since the response that
+    /// `validators` came from.
+    fn get(&self, url: &str, validators: &Validators) -> Result<Fetched<Response>, FetchError> {
+        let response = self.call(&validators.apply(Request::new(url)))?;
+        match response.status {
+            304 => return Ok(Fetched::NotModified),
+            400.. => return Err(FetchError::status(url, response.status)),
+            _ => (),
+        }
+        let validators = Validators::from_response(&response);
+        Ok(Fetched::Modified(response, validators))
+    }
+
+    /// Fetches the body of `url` unless it hasn't changed since the response
+    /// that `validators` came from.
+    pub fn get_bytes_if_modified(
+        &self,
+        url: &str,
+        validators: &Validators,
+    ) -> Result<Fetched<Vec<u8>>, FetchError> {
+        let Fetched::Modified(response, validators) = self.get(url, validators)? else {
+            return Ok(Fetched::NotModified);
+        };
+        let mut buffer = vec![];
+        response
+            .into_reader()
+            .read_to_end(&mut buffer)
+            .map_err(FetchError::body)?;
+        Ok(Fetched::Modified(buffer, validators))
+    }
+
+    /// Fetches the body of `url`.
+    pub fn get_bytes(&self, url: &str) -> Result<Vec<u8>, FetchError> {
+        self.get_bytes_if_modified(url, &Validators::default())?
+            .modified()
+            .map(|(bytes, _)| bytes)
+            .ok_or_else(|| FetchError::new(ErrorKind::ClientError, "unexpected 304 response"))
+    }
+
+    /// Downloads `url` to `path`.
+    ///
+    /// The body is written to a `.part` file next to `path` and then renamed
+    /// into place, so `path` never contains a truncated download. If an
+    /// earlier download of the same URL was interrupted, it is resumed if the
+    /// server supports range requests and the file hasn't changed since.
+    ///
+    /// If `path` hasn't changed since the response that `validators` came
+    /// from, it is left alone. Otherwise returns the size of the file.
+    pub fn download_file(
+        &self,
+        url: &str,
+        path: &Path,
+        validators: &Validators,
+    ) -> Result<Fetched<u64>, FetchError> {
+        let part_path = partial_download_path(url, path);
+        let part_validators_path = partial_validators_path(&part_path);
+        let mut resume_from = std::fs::metadata(&part_path).map_or(0, |m| m.len());
+
+        // Only resume if we can ask the server to send the rest of the same
+        // version of the file, or else the two halves might not match.
+        let if_range = std::fs::read_to_string(&part_validators_path)
+            .ok()
+            .and_then(|s| serde_json::from_str::<Validators>(&s).ok())
+            .and_then(|v| v.if_range().map(str::to_owned));
+        let mut request = Request::new(url);
+        match if_range {
+            Some(if_range) if resume_from > 0 => {
+                request = request
+                    .header("Range", format!("bytes={resume_from}-"))
+                    .header("If-Range", if_range);
+            }
+            _ => {
+                if resume_from > 0 {
+                    remove_partial_download(&part_path).map_err(FetchError::disk)?;
+                    resume_from = 0;
+                }
+                request = validators.apply(request);
+            }
+        }
+        let response = self.call(&request)?;
+        match response.status {
+            // The partial file is longer than the real one, so it must be
+            // from some other version of the image. Start over.
+            416 if resume_from > 0 => {
+                remove_partial_download(&part_path).map_err(FetchError::disk)?;
+                return self.download_file(url, path, validators);
+            }
+            304 => return Ok(Fetched::NotModified),
+            400.. => return Err(FetchError::status(url, response.status)),
+            _ => (),
+        }
+        let new_validators = Validators::from_response(&response);
+
+        let resumed = response.status == 206
+            && response
+                .header("Content-Range")
+                .and_then(|range| range.strip_prefix("bytes ")?.split_once('-'))
+                .is_some_and(|(start, _)| start.parse() == Ok(resume_from));
+        if response.status == 206 && !resumed {
+            // Some other part of the file, which we can't use.
+            if resume_from > 0 {
+                remove_partial_download(&part_path).map_err(FetchError::disk)?;
+                return self.download_file(url, path, validators);
+            }
+            return Err(FetchError::new(
+                ErrorKind::ServerError,
+                format!("unexpected partial response for {url}"),
+            ));
+        }
+        let mut file = if resumed {
+            std::fs::OpenOptions::new().append(true).open(&part_path)
+        } else {
+            let json = serde_json::to_string(&new_validators)
+                .expect("validators should serialize to JSON");
+            std::fs::write(&part_validators_path, json).map_err(FetchError::disk)?;
+            std::fs::File::create(&part_path)
+        }
+        .map_err(FetchError::disk)?;
+
+        let mut reader = response.into_reader();
+        let mut buffer = [0; 64 * 1024];
+        loop {
+            let len = reader.read(&mut buffer).map_err(FetchError::body)?;
+            if len == 0 {
+                break;
+            }
+            file.write_all(&buffer[..len]).map_err(FetchError::disk)?;
+        }
+        file.sync_all().map_err(FetchError::disk)?;
+        let len = file.metadata().map_err(FetchError::disk)?.len();
+        drop(file);
+
+        std::fs::rename(&part_path, path).map_err(FetchError::disk)?;
+        remove_if_exists(&part_validators_path).map_err(FetchError::disk)?;
+        Ok(Fetched::Modified(len, new_validators))
+    }
+
+    /// Fetches `url` and parses it as JSON, unless it hasn't changed since the
+    /// response that `validators` came from.
+    pub fn get_json_if_modified<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        validators: &Validators,
+    ) -> Result<Fetched<T>, FetchError> {
+        let Fetched::Modified(body, validators) = self.get_bytes_if_modified(url, validators)?
+        else {
+            return Ok(Fetched::NotModified);
+        };
+        let value =
+            serde_json::from_slice(&body).map_err(|e| FetchError::new(ErrorKind::Decode, e))?;
+        Ok(Fetched::Modified(value, validators))
+    }
+
+    /// Fetches `url` and parses it as JSON.
+    pub fn get_json<T: DeserializeOwned>(&self, url: &str) -> Result<T, FetchError> {
+        let body = self.get_bytes(url)?;
+        serde_json::from_slice(&body).map_err(|e| FetchError::new(ErrorKind::Decode, e))
+    }
+}
+
+/// HTTP cache validators from an earlier response, used to skip downloading
+/// things that haven't changed.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Validators {
+    /// `ETag` header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    /// `Last-Modified` header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+impl Validators {
+    fn from_response(response: &Response) -> Self {
+        Self {
+            etag: response.header("ETag").map(str::to_owned),
+            last_modified: response.header("Last-Modified").map(str::to_owned),
+        }
+    }
+
+    /// Makes `request` conditional on the resource having changed.
+    fn apply(&self, mut request: Request) -> Request {
+        if let Some(etag) = &self.etag {
+            request = request.header("If-None-Match", etag);
+        }
+        if let Some(last_modified) = &self.last_modified {
+            request = request.header("If-Modified-Since", last_modified);
+        }
+        request
+    }
+
+    /// Returns a value for `If-Range` that only matches this exact version of
+    /// the resource, if there is one. Weak ETags can't be used for this.
+    fn if_range(&self) -> Option<&str> {
+        self.etag
+            .as_deref()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+}
+
+/// Result of a conditional request.
+#[derive(Debug)]
+pub enum Fetched<T> {
+    /// The resource changed, so here is the new version.
+    Modified(T, Validators),
+    /// The resource hasn't changed.
+    NotModified,
+}
+impl<T> Fetched<T> {
+    pub fn modified(self) -> Option<(T, Validators)> {
+        match self {
+            Fetched::Modified(value, validators) => Some((value, validators)),
+            Fetched::NotModified => None,
+        }
+    }
+}
+
+/// Longest that a `Retry-After` header can make us wait, so that a bogus one
+/// can't stall every job indefinitely.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Longest time between requests that a rate limit can ask for.
+const MAX_REQUEST_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Parses the value of a `Retry-After` header, which is either a number of
+/// seconds or an HTTP date. The delay is capped at [`MAX_RETRY_AFTER`].
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let delay = match value.parse() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            date.duration_since(SystemTime::now()).unwrap_or_default()
+        }
+    };
+    Some(delay.min(MAX_RETRY_AFTER))
+}
+
+/// Returns the time between requests for a limit of `requests_per_second`,
+/// or an error if the limit is negative or too low to use. Zero means no
+/// limit.
+pub fn request_interval(requests_per_second: f64) -> Result<Duration, String> {
+    if requests_per_second == 0.0 {
+        return Ok(Duration::ZERO);
+    }
+    if requests_per_second.is_nan() || requests_per_second < 0.0 {
+        return Err("rate limit must be 0 or a positive number".to_owned());
+    }
+    match Duration::try_from_secs_f64(1.0 / requests_per_second) {
+        Ok(interval) if interval <= MAX_REQUEST_INTERVAL => Ok(interval),
+        _ => Err("rate limit must allow at least one request per day".to_owned()),
+    }
+}
+
+/// Limits how often requests are sent.
+#[derive(Debug)]
+struct RateLimiter {
+    /// Minimum time between requests.
+    interval: Duration,
+    /// Earliest time that the next request may be sent.
+    next: Mutex<Instant>,
+}
+impl RateLimiter {
+    /// Constructs a rate limiter. If `requests_per_second` is zero, there is no
+    /// limit.
+    ///
+    /// The config and command line only allow limits that
+    /// [`request_interval()`] accepts, so the fallback is never used.
+    fn new(requests_per_second: f64) -> Self {
+        let interval = request_interval(requests_per_second).unwrap_or(MAX_REQUEST_INTERVAL);
+        Self {
+            interval,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Blocks until it is our turn to send a request.
+    fn wait(&self) {
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = Instant::max(*next, Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        std::thread::sleep(slot.saturating_duration_since(Instant::now()));
+    }
+
+    /// Delays all requests until at least `delay` from now.
+    fn pause(&self, delay: Duration) {
+        let mut next = self.next.lock().unwrap();
+        *next = Instant::max(*next, Instant::now() + delay);
+    }
+}
+
+/// Returns the path of the temporary file used while downloading `url` to
+/// `path`, such as `cache/img/614.woodpecker_2x.png.part`.
+///
+/// Different URLs get different temporary files so that a partial download is
+/// only ever resumed from the same URL.
+pub fn partial_download_path(url: &str, path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let url_file_name = url.rsplit('/').next().unwrap_or_default();
+    path.with_file_name(format!("{stem}.{url_file_name}.part"))
+}
+
+/// Returns the file that holds the validators of the response that the
+/// partial download in `part_path` came from.
+pub fn partial_validators_path(part_path: &Path) -> PathBuf {
+    let mut file_name = part_path.file_name().unwrap_or_default().to_owned();
+    file_name.push(".validators");
+    part_path.with_file_name(file_name)
+}
+
+/// Deletes the partial download in `part_path` along with its validators.
+pub fn remove_partial_download(part_path: &Path) -> std::io::Result<()> {
+    remove_if_exists(part_path)?;
+    remove_if_exists(&partial_validators_path(part_path))
+}
+
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// How many times to try again after a transient error.
+#[derive(Debug, Copy, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of retries after the first attempt.
+    pub max_retries: u32,
+    /// Delay before the first retry. Each retry after that waits twice as long.
+    pub base_delay: Duration,
+    /// Maximum delay between retries.
+    pub max_delay: Duration,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+impl RetryPolicy {
+    /// Calls `f` until it succeeds, it returns a permanent error, or we run
+    /// out of retries.
+    pub fn run<T>(&self, mut f: impl FnMut() -> Result<T, FetchError>) -> Result<T, FetchError> {
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(e) if e.kind.is_transient() && attempt < self.max_retries => {
+                    std::thread::sleep(self.delay(attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Returns the exponential backoff delay before retry number `attempt`,
+    /// with jitter so that parallel jobs don't all retry at the same time.
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_delay);
+        backoff / 2 + backoff.mul_f64(fastrand::f64() / 2.0)
+    }
+}
@@ -0,0 +1,562 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::fetch::{ErrorKind, FetchError};
+
+/// HTTP GET request.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub url: String,
+    pub headers: Vec<(&'static str, String)>,
+}
+impl Request {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_owned(),
+            headers: vec![],
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// HTTP response. Error statuses are responses too, not errors.
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    body: Box<dyn Read + Send>,
+}
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .finish_non_exhaustive()
+    }
+}
+impl Response {
+    pub fn new(status: u16, headers: Vec<(String, String)>, body: Box<dyn Read + Send>) -> Self {
+        Self {
+            status,
+            headers,
+            body,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn into_reader(self) -> Box<dyn Read + Send> {
+        self.body
+    }
+}
+
+/// Backend that sends HTTP requests for a [`crate::fetch::Client`].
+pub trait Fetcher: fmt::Debug + Send + Sync {
+    /// Sends a GET request. Only failures to get any response at all are
+    /// returned as errors.
+    fn get(&self, request: &Request) -> Result<Response, FetchError>;
+}
+
+/// Fetcher that uses the network.
+#[derive(Debug, Clone)]
+pub struct UreqFetcher {
+    agent: ureq::Agent,
+}
+impl UreqFetcher {
+    pub fn new(user_agent: &str) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(10))
+                .timeout_read(Duration::from_secs(30))
+                .user_agent(user_agent)
+                .build(),
+        }
+    }
+}
+impl Fetcher for UreqFetcher {
+    fn get(&self, request: &Request) -> Result<Response, FetchError> {
+        let mut ureq_request = self.agent.get(&request.url);
+        for (name, value) in &request.headers {
+            ureq_request = ureq_request.set(name, value);
+        }
+        let response = match ureq_request.call() {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(e) => return Err(e.into()),
+        };
+        let headers = response
+            .headers_names()
+            .into_iter()
+            .filter_map(|name| {
+                let value = response.header(&name)?.to_owned();
+                Some((name, value))
+            })
+            .collect();
+        Ok(Response::new(
+            response.status(),
+            headers,
+            Box::new(response.into_reader()),
+        ))
+    }
+}
+
+/// Fetcher that serves recorded responses from a directory instead of using
+/// the network.
+///
+/// The response for `https://xkcd.com/614/info.0.json` is read from
+/// `<dir>/xkcd.com/614/info.0.json`, and URLs with no file get a 404. A
+/// [`FixtureMeta`] file next to it, such as `info.0.json.meta.json`, can set
+/// the status and headers or simulate a network error.
+#[derive(Debug, Clone)]
+pub struct FixtureFetcher {
+    dir: PathBuf,
+}
+impl FixtureFetcher {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+impl Fetcher for FixtureFetcher {
+    fn get(&self, request: &Request) -> Result<Response, FetchError> {
+        let Some(path) = fixture_path(&self.dir, &request.url) else {
+            return Err(FetchError::new(
+                ErrorKind::ClientError,
+                format!("no fixture path for {}", request.url),
+            ));
+        };
+
+        let meta_path = fixture_meta_path(&path);
+        let meta = match std::fs::read_to_string(&meta_path) {
+            Ok(s) => serde_json::from_str(&s).map_err(|e| {
+                FetchError::new(
+                    ErrorKind::Decode,
+                    format!("error parsing {}: {e}", meta_path.display()),
+                )
+            })?,
+            Err(_) => FixtureMeta::default(),
+        };
+        if let Some(kind) = meta.error {
+            return Err(FetchError::new(kind, format!("simulated {kind}")));
+        }
+        let headers: Vec<(String, String)> = meta.headers.into_iter().collect();
+        let response = |status, body: Vec<u8>| {
+            Response::new(
+                status,
+                headers.clone(),
+                Box::new(std::io::Cursor::new(body)),
+            )
+        };
+
+        let not_modified = [
+            ("If-None-Match", "ETag"),
+            ("If-Modified-Since", "Last-Modified"),
+        ]
+        .into_iter()
+        .any(|(request_header, response_header)| {
+            let expected = headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(response_header));
+            request
+                .get_header(request_header)
+                .is_some_and(|v| expected.is_some_and(|(_, expected)| v == expected))
+        });
+        if not_modified {
+            return Ok(response(304, vec![]));
+        }
+
+        match std::fs::read(&path) {
+            Ok(body) => Ok(response(meta.status, body)),
+            Err(_) if meta.status != 200 => Ok(response(meta.status, vec![])),
+            Err(_) => Ok(response(404, vec![])),
+        }
+    }
+}
+
+/// Fetcher that saves every response from another fetcher to a directory, in
+/// the format that [`FixtureFetcher`] reads.
+///
+/// Range and conditional headers are left out of requests so that every
+/// recorded response is complete.
+#[derive(Debug)]
+pub struct RecordingFetcher<F> {
+    inner: F,
+    dir: PathBuf,
+}
+impl<F: Fetcher> RecordingFetcher<F> {
+    pub fn new(inner: F, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            inner,
+            dir: dir.into(),
+        }
+    }
+
+    fn save(&self, path: &Path, meta: &FixtureMeta, body: Option<&[u8]>) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let meta_path = fixture_meta_path(path);
+        if *meta == FixtureMeta::default() {
+            match std::fs::remove_file(&meta_path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        } else {
+            std::fs::write(&meta_path, serde_json::to_string_pretty(meta)?)?;
+        }
+        match body {
+            Some(body) => std::fs::write(path, body),
+            None => match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            },
+        }
+    }
+}
+impl<F: Fetcher> Fetcher for RecordingFetcher<F> {
+    fn get(&self, request: &Request) -> Result<Response, FetchError> {
+        let Some(path) = fixture_path(&self.dir, &request.url) else {
+            return self.inner.get(request);
+        };
+
+        let mut request = request.clone();
+        request.headers.retain(|(name, _)| {
+            !["Range", "If-Range", "If-None-Match", "If-Modified-Since"]
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case(name))
+        });
+
+        let response = match self.inner.get(&request) {
+            Ok(response) => response,
+            Err(e) => {
+                let meta = FixtureMeta {
+                    error: Some(e.kind),
+                    ..Default::default()
+                };
+                self.save(&path, &meta, None).map_err(FetchError::disk)?;
+                return Err(e);
+            }
+        };
+
+        let status = response.status;
+        let headers = response.headers.clone();
+        let mut body = vec![];
+        response
+            .into_reader()
+            .read_to_end(&mut body)
+            .map_err(FetchError::body)?;
+
+        let meta = FixtureMeta {
+            status,
+            headers: headers.iter().cloned().collect(),
+            error: None,
+        };
+        self.save(&path, &meta, Some(&body))
+            .map_err(FetchError::disk)?;
+
+        Ok(Response::new(
+            status,
+            headers,
+            Box::new(std::io::Cursor::new(body)),
+        ))
+    }
+}
+
+/// Status, headers, or error for a recorded response.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct FixtureMeta {
+    pub status: u16,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// Error to return instead of a response.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorKind>,
+}
+impl Default for FixtureMeta {
+    fn default() -> Self {
+        Self {
+            status: 200,
+            headers: BTreeMap::new(),
+            error: None,
+        }
+    }
+}
+
+/// Returns the file in `dir` that holds the response for `url`, or `None` if
+/// the URL can't be mapped to a file safely.
+pub fn fixture_path(dir: &Path, url: &str) -> Option<PathBuf> {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let without_query = without_scheme.split(['?', '#']).next()?;
+    let relative = Path::new(without_query);
+    let is_safe = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    (is_safe && !without_query.is_empty()).then(|| dir.join(relative))
+}
+
+/// Returns the file that holds the [`FixtureMeta`] for the response in `path`.
+pub fn fixture_meta_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(".meta.json");
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::config::Config;
+    use crate::date::DateRange;
+    use crate::download::{download_comics, DownloadOptions, Refresh};
+    use crate::fetch::{Client, RetryPolicy};
+    use crate::progress::{Progress, ProgressFormat};
+    use crate::store::{Comic, Store};
+
+    const MAX_RETRIES: u32 = 3;
+
+    /// Fixture directory that is deleted when dropped.
+    struct Fixtures(PathBuf);
+    impl Fixtures {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("xkcd-rank-test-{}-{name}", std::process::id(),));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        /// Adds a recorded response for the metadata of comic `n`.
+        fn add_comic(&self, n: usize, meta: FixtureMeta) {
+            let path = fixture_path(&self.0, &comic_url(n)).unwrap();
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, serde_json::to_string(&comic(n)).unwrap()).unwrap();
+            if meta != FixtureMeta::default() {
+                let meta = serde_json::to_string(&meta).unwrap();
+                std::fs::write(fixture_meta_path(&path), meta).unwrap();
+            }
+        }
+    }
+    impl Drop for Fixtures {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Fetcher that counts the requests for each URL.
+    #[derive(Debug)]
+    struct CountingFetcher {
+        inner: FixtureFetcher,
+        urls: Mutex<Vec<String>>,
+    }
+    impl Fetcher for CountingFetcher {
+        fn get(&self, request: &Request) -> Result<Response, FetchError> {
+            self.urls.lock().unwrap().push(request.url.clone());
+            self.inner.get(request)
+        }
+    }
+    impl CountingFetcher {
+        fn requests_for(&self, url: &str) -> usize {
+            self.urls
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|u| *u == url)
+                .count()
+        }
+    }
+
+    fn comic_url(n: usize) -> String {
+        format!("https://xkcd.com/{n}/info.0.json")
+    }
+
+    /// Returns a comic with no image, so that syncing it doesn't write to the
+    /// cache directory.
+    fn comic(n: usize) -> Comic {
+        Comic {
+            num: n,
+            year: "2020".to_owned(),
+            month: "1".to_owned(),
+            day: "1".to_owned(),
+            title: format!("Comic {n}"),
+            safe_title: format!("Comic {n}"),
+            ..Default::default()
+        }
+    }
+
+    /// Syncs `nums` from `fixtures` into `store`. Returns the summary as JSON
+    /// and the fetcher that was used.
+    fn sync(
+        fixtures: &Fixtures,
+        store: &mut Store,
+        nums: &[usize],
+        refresh: Refresh,
+    ) -> (serde_json::Value, Arc<CountingFetcher>) {
+        let fetcher = Arc::new(CountingFetcher {
+            inner: FixtureFetcher::new(&fixtures.0),
+            urls: Mutex::new(vec![]),
+        });
+        let config = Config {
+            requests_per_second: 0.0,
+            ..Default::default()
+        };
+        let client = Client::with_fetcher(&config, fetcher.clone());
+        let opts = DownloadOptions {
+            refresh,
+            full: false,
+            jobs: 2,
+            retry: RetryPolicy {
+                max_retries: MAX_RETRIES,
+                base_delay: Duration::ZERO,
+                max_delay: Duration::ZERO,
+            },
+            progress: ProgressFormat::Human,
+            count: None,
+            dry_run: false,
+            only: None,
+            dates: DateRange::default(),
+            checkpoint_every: None,
+            checkpoint_interval: None,
+        };
+        let progress = Progress::new(opts.progress);
+        let summary = download_comics(store, &client, nums.iter().copied(), &opts, &progress);
+        (serde_json::to_value(&summary).unwrap(), fetcher)
+    }
+
+    #[test]
+    fn not_found_is_not_retried() {
+        let fixtures = Fixtures::new("not-found");
+        fixtures.add_comic(1, FixtureMeta::default());
+        fixtures.add_comic(2, FixtureMeta::default());
+
+        let mut store = Store::default();
+        let (summary, fetcher) = sync(&fixtures, &mut store, &[1, 2, 3], Refresh::default());
+
+        assert_eq!(summary["added"], serde_json::json!([1, 2]));
+        assert_eq!(summary["failed"], serde_json::json!({ "not_found": [3] }));
+        assert_eq!(store.get_comic(2).unwrap().title, "Comic 2");
+        assert!(store.get_comic(3).is_none());
+        assert_eq!(fetcher.requests_for(&comic_url(3)), 1);
+    }
+
+    #[test]
+    fn timeout_is_retried() {
+        let fixtures = Fixtures::new("timeout");
+        fixtures.add_comic(
+            1,
+            FixtureMeta {
+                error: Some(ErrorKind::Timeout),
+                ..Default::default()
+            },
+        );
+
+        let mut store = Store::default();
+        let (summary, fetcher) = sync(&fixtures, &mut store, &[1], Refresh::default());
+
+        assert_eq!(summary["failed"], serde_json::json!({ "timeout": [1] }));
+        assert!(store.get_comic(1).is_none());
+        let attempts = 1 + MAX_RETRIES as usize;
+        assert_eq!(fetcher.requests_for(&comic_url(1)), attempts);
+    }
+
+    #[test]
+    fn not_modified_is_unchanged() {
+        let fixtures = Fixtures::new("not-modified");
+        let etag = "\"v1\"";
+        fixtures.add_comic(
+            1,
+            FixtureMeta {
+                headers: [("ETag".to_owned(), etag.to_owned())].into(),
+                ..Default::default()
+            },
+        );
+
+        let mut store = Store::default();
+        store.insert_comic(comic(1));
+        store.update_validators(1, |v| v.metadata.etag = Some(etag.to_owned()));
+        store.mark_saved();
+        let refresh = Refresh {
+            metadata: true,
+            images: false,
+        };
+        let (summary, fetcher) = sync(&fixtures, &mut store, &[1], refresh);
+
+        assert_eq!(summary["unchanged"], 1);
+        assert_eq!(summary["added"], serde_json::json!([]));
+        assert_eq!(summary["failed"], serde_json::json!({}));
+        assert_eq!(fetcher.requests_for(&comic_url(1)), 1);
+    }
+
+    #[test]
+    fn unexpected_not_modified_is_an_error() {
+        let fixtures = Fixtures::new("unexpected-304");
+        fixtures.add_comic(
+            1,
+            FixtureMeta {
+                status: 304,
+                ..Default::default()
+            },
+        );
+        fixtures.add_comic(2, FixtureMeta::default());
+
+        let mut store = Store::default();
+        let (summary, _) = sync(&fixtures, &mut store, &[1, 2], Refresh::default());
+
+        assert_eq!(
+            summary["failed"],
+            serde_json::json!({ "client_error": [1] })
+        );
+        assert_eq!(summary["added"], serde_json::json!([2]));
+        assert!(store.get_comic(1).is_none());
+    }
+
+    #[test]
+    fn status_from_meta_file() {
+        let fixtures = Fixtures::new("status");
+        fixtures.add_comic(
+            1,
+            FixtureMeta {
+                status: 503,
+                ..Default::default()
+            },
+        );
+        fixtures.add_comic(
+            2,
+            FixtureMeta {
+                status: 403,
+                ..Default::default()
+            },
+        );
+
+        let mut store = Store::default();
+        let (summary, fetcher) = sync(&fixtures, &mut store, &[1, 2], Refresh::default());
+
+        assert_eq!(
+            summary["failed"],
+            serde_json::json!({ "server_error": [1], "client_error": [2] }),
+        );
+        assert_eq!(summary["added"], serde_json::json!([]));
+        let attempts = 1 + MAX_RETRIES as usize;
+        assert_eq!(fetcher.requests_for(&comic_url(1)), attempts);
+        assert_eq!(fetcher.requests_for(&comic_url(2)), 1);
+    }
+}
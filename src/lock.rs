@@ -0,0 +1,65 @@
+use std::fmt;
+use std::fs::{File, TryLockError};
+use std::io::Write;
+
+use eyre::{Result, WrapErr};
+
+/// Exclusive advisory lock on the cache directory, held for as long as this
+/// exists.
+///
+/// The operating system releases the lock when the process exits, even if it
+/// crashes, so a leftover lock file never keeps the store locked.
+#[derive(Debug)]
+pub struct StoreLock {
+    _file: File,
+}
+impl StoreLock {
+    /// Locks the cache directory, or returns a [`Locked`] error if another
+    /// process has it locked.
+    pub fn acquire() -> Result<Self> {
+        std::fs::create_dir_all(crate::util::cache_dir())?;
+        let path = crate::util::lock_path();
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .wrap_err_with(|| format!("error opening {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                let pid = std::fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|s| s.trim().parse().ok());
+                return Err(Locked { pid }.into());
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).wrap_err_with(|| format!("error locking {}", path.display()));
+            }
+        }
+
+        // Only for telling the user who has the lock.
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+
+        Ok(Self { _file: file })
+    }
+}
+
+/// Error when another process is using the store.
+#[derive(Debug, Copy, Clone)]
+pub struct Locked {
+    /// ID of the process that has the lock, if we know it.
+    pid: Option<u32>,
+}
+impl fmt::Display for Locked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the data store is in use by another process")?;
+        if let Some(pid) = self.pid {
+            write!(f, " (PID {pid})")?;
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for Locked {}
@@ -1,14 +1,29 @@
+use std::sync::Arc;
+
 use clap::Parser;
 use eframe::{
     egui::{self, Widget},
     emath::TSTransform,
 };
-use eyre::Result;
-use store::Store;
-
+use eyre::{Result, WrapErr};
+use fetch::Client;
+use store::{ComicKind, Store};
+
+mod backup;
+mod config;
+mod date;
+mod db;
 mod download;
+mod fetch;
+mod fetcher;
+mod lock;
+mod overrides;
+mod progress;
+mod select;
 mod store;
+mod text;
 mod util;
+mod verify;
 
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 pub struct Tier {
@@ -68,54 +83,277 @@ const TIERS: &[Tier] = &[
 /// xkcd downloader and tier list
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
+#[command(group(clap::ArgGroup::new("sync").args(["download", "verify"]).multiple(true)))]
 struct Args {
     /// Download all comics instead of showing the UI.
     #[arg(short, long)]
     download: bool,
 
-    /// Redownload comics that we have already downloaded.
+    /// Check downloaded images and download invalid ones again.
+    #[arg(long)]
+    verify: bool,
+
+    /// List backups of the data store, or roll back to the one called NAME.
+    #[arg(long, value_name = "NAME")]
+    restore: Option<Option<String>>,
+
+    /// Redownload metadata and images for comics that we have already
+    /// downloaded. Tier assignments are kept.
     #[arg(short, long)]
     redownload: bool,
+
+    /// Redownload metadata for comics that we have already downloaded.
+    #[arg(long)]
+    refresh_metadata: bool,
+
+    /// Redownload images for comics that we have already downloaded.
+    #[arg(long)]
+    refresh_images: bool,
+
+    /// Print what would be downloaded or deleted without changing anything.
+    #[arg(long, requires = "sync")]
+    dry_run: bool,
+
+    /// Number of the latest comic. If omitted, xkcd is asked.
+    #[arg(long)]
+    count: Option<usize>,
+
+    /// Only download these comics, such as `1000..1100`, `404,1190,1608`, or
+    /// `tier:S`. Ranges are inclusive.
+    #[arg(long, value_name = "COMICS")]
+    only: Option<select::Selection>,
+
+    /// Only download comics published on or after this date, such as
+    /// `2012-01-01`.
+    #[arg(long, value_name = "DATE")]
+    since: Option<date::Date>,
+
+    /// Only download comics published on or before this date.
+    #[arg(long, value_name = "DATE")]
+    until: Option<date::Date>,
+
+    /// If the data store can't be read, move it aside and restore the newest
+    /// backup instead of stopping.
+    #[arg(long)]
+    force: bool,
+
+    /// Save progress after downloading this many comics, or 0 to never save
+    /// partway through.
+    #[arg(long, value_name = "N", default_value_t = 100)]
+    checkpoint_every: usize,
+
+    /// Save progress after this many seconds, or 0 to never save partway
+    /// through.
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    checkpoint_secs: u64,
+
+    /// Check every comic instead of only ones newer than the newest downloaded
+    /// comic.
+    #[arg(long)]
+    full: bool,
+
+    /// Number of comics to download in parallel.
+    #[arg(short, long, default_value_t = 4)]
+    jobs: usize,
+
+    /// Number of times to retry after a timeout, network error, or server
+    /// error.
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+
+    /// Base URL of xkcd or a mirror of it [default: https://xkcd.com]
+    #[arg(long)]
+    base_url: Option<String>,
+
+    /// Scheme and host to download images from instead of imgs.xkcd.com.
+    #[arg(long)]
+    img_host: Option<String>,
+
+    /// Save every HTTP response to this directory, so that the run can be
+    /// replayed later with `--replay`.
+    #[arg(long, value_name = "DIR", conflicts_with = "replay")]
+    record: Option<std::path::PathBuf>,
+
+    /// Serve all HTTP requests from responses in this directory, such as ones
+    /// saved by `--record`, instead of using the network.
+    #[arg(long, value_name = "DIR", alias = "fixtures")]
+    replay: Option<std::path::PathBuf>,
+
+    /// How to report download progress.
+    #[arg(long, value_enum, default_value_t)]
+    progress: progress::ProgressFormat,
+
+    /// Maximum number of HTTP requests per second, or 0 for no limit
+    /// [default: 5]
+    #[arg(long, value_name = "RPS", value_parser = parse_rate_limit)]
+    rate_limit: Option<f64>,
+}
+
+/// Parses a rate limit, rejecting ones that the client can't use.
+fn parse_rate_limit(s: &str) -> Result<f64, String> {
+    let requests_per_second = s.parse().map_err(|e| format!("{e}"))?;
+    fetch::request_interval(requests_per_second)?;
+    Ok(requests_per_second)
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
-    if args.download {
-        crate::download::download_all_comics(args.redownload)?;
+    let mut config = config::Config::load()?;
+    overrides::Overrides::load()?.install();
+    if let Some(base_url) = args.base_url {
+        config.base_url = base_url;
+    }
+    if let Some(img_host) = args.img_host {
+        config.img_host = Some(img_host);
+    }
+    if let Some(rate_limit) = args.rate_limit {
+        config.requests_per_second = rate_limit;
+    }
+    backup::set_backups_to_keep(config.backups);
+
+    if let Some(name) = args.restore {
+        match name {
+            None => backup::print_list(),
+            Some(name) => {
+                let path = backup::find(&name)?;
+                Store::restore(&path)?;
+                println!("Restored data store from {}", path.display());
+            }
+        }
+        return Ok(());
+    }
+    let client = if let Some(dir) = args.replay {
+        Client::with_fetcher(&config, Arc::new(fetcher::FixtureFetcher::new(dir)))
+    } else if let Some(dir) = args.record {
+        let user_agent = config
+            .user_agent
+            .as_deref()
+            .unwrap_or(fetch::DEFAULT_USER_AGENT);
+        let fetcher = fetcher::UreqFetcher::new(user_agent);
+        Client::with_fetcher(
+            &config,
+            Arc::new(fetcher::RecordingFetcher::new(fetcher, dir)),
+        )
+    } else {
+        Client::new(&config)
+    };
+
+    let download_options = crate::download::DownloadOptions {
+        refresh: crate::download::Refresh {
+            metadata: args.redownload || args.refresh_metadata,
+            images: args.redownload || args.refresh_images,
+        },
+        full: args.full,
+        jobs: args.jobs,
+        retry: crate::fetch::RetryPolicy {
+            max_retries: args.retries,
+            ..Default::default()
+        },
+        progress: args.progress,
+        count: args.count,
+        dry_run: args.dry_run,
+        only: args.only,
+        dates: date::DateRange {
+            since: args.since,
+            until: args.until,
+        },
+        checkpoint_every: (args.checkpoint_every > 0).then_some(args.checkpoint_every),
+        checkpoint_interval: (args.checkpoint_secs > 0)
+            .then(|| std::time::Duration::from_secs(args.checkpoint_secs)),
+    };
+
+    // A dry run never writes to the store, so it doesn't need to lock it.
+    let dry_run = args.dry_run;
+    let store = match dry_run {
+        true => Store::load_read_only().wrap_err("error loading data store"),
+        false => Store::load_or_recover(args.force),
+    };
+
+    if args.verify || args.download {
+        let mut store = match store {
+            Ok(store) => store,
+            Err(e) => {
+                eprintln!("Error: {e:#}");
+                if !args.force && !dry_run && !e.is::<lock::Locked>() {
+                    eprintln!("Run with --force to move it aside and restore the newest backup.");
+                }
+                std::process::exit(1);
+            }
+        };
+        if args.verify {
+            crate::verify::verify_images(&mut store, &client, download_options)?;
+        } else {
+            crate::download::download_all_comics(&mut store, &client, download_options)?;
+        }
     } else {
         let native_options = eframe::NativeOptions::default();
         eframe::run_native(
             "xkcd Rank",
             native_options,
-            Box::new(|cc| Ok(Box::new(App::new(cc)))),
+            Box::new(|cc| Ok(Box::new(App::new(cc, client, store)))),
         )?;
     }
     Ok(())
 }
 
-#[derive(Default)]
 struct App {
     data: Store,
+    client: Client,
+    /// Why the data store couldn't be loaded, until the user decides what to
+    /// do about it.
+    load_error: Option<String>,
+    /// Whether the data store couldn't be loaded because another process is
+    /// using it.
+    locked: bool,
+    /// Newest backup that could replace a data store that couldn't be loaded.
+    backup: Option<std::path::PathBuf>,
 
     n: usize,
     img_transform: TSTransform,
+    /// Date typed into the "go to date" box.
+    date_query: String,
 }
 
 impl App {
-    fn new(cc: &eframe::CreationContext<'_>) -> Self {
+    fn new(cc: &eframe::CreationContext<'_>, client: Client, store: Result<Store>) -> Self {
         // Customize egui here with cc.egui_ctx.set_fonts and cc.egui_ctx.set_visuals.
         // Restore app state using cc.storage (requires the "persistence" feature).
         // Use the cc.gl (a glow::Context) to create graphics shaders and buffers that you can use
         // for e.g. egui::PaintCallback.
         egui_extras::install_image_loaders(&cc.egui_ctx);
         cc.egui_ctx.set_zoom_factor(1.5);
-        Self {
-            data: Store::load(),
+        let (data, load_error) = match store {
+            Ok(store) => (store, None),
+            Err(e) => {
+                eprintln!("Error: {e:#}");
+                (Store::default(), Some(e))
+            }
+        };
+        let mut app = Self {
+            data,
+            client,
+            load_error: None,
+            locked: false,
+            backup: None,
 
             n: 1,
             img_transform: TSTransform::IDENTITY,
+            date_query: String::new(),
+        };
+        if let Some(e) = load_error {
+            app.set_load_error(e);
         }
+        app
+    }
+
+    fn set_load_error(&mut self, e: eyre::Report) {
+        self.locked = e.is::<lock::Locked>();
+        self.backup = match self.locked {
+            true => None,
+            false => Store::newest_valid_backup(),
+        };
+        self.load_error = Some(format!("{e:#}"));
     }
 
     fn show_comic_selector(&mut self, ui: &mut egui::Ui) {
@@ -128,11 +366,12 @@ impl App {
                 self.n -= 1;
                 self.reset_img_transform();
             }
+            self.show_date_search(ui);
             ui.with_layout(egui::Layout::left_to_right(egui::Align::TOP), |ui| {
                 ui.spacing_mut().slider_width = ui.available_width()
                     - ui.spacing().interact_size.x
                     - ui.spacing().item_spacing.x;
-                let r = egui::Slider::new(&mut self.n, 1..=self.data.comics.len() - 1)
+                let r = egui::Slider::new(&mut self.n, 1..=self.data.comics.len().max(2) - 1)
                     .trailing_fill(true)
                     .drag_value_speed(0.25)
                     .ui(ui);
@@ -143,17 +382,47 @@ impl App {
         });
     }
 
+    /// Shows a box to jump to the first comic published on or after a date.
+    fn show_date_search(&mut self, ui: &mut egui::Ui) {
+        let date = self.date_query.parse::<date::Date>();
+        let is_invalid = date.is_err() && !self.date_query.is_empty();
+        let mut text_edit = egui::TextEdit::singleline(&mut self.date_query)
+            .hint_text("YYYY-MM-DD")
+            .desired_width(ui.spacing().interact_size.x * 2.0);
+        if is_invalid {
+            text_edit = text_edit.text_color(ui.visuals().error_fg_color);
+        }
+        let r = text_edit
+            .ui(ui)
+            .on_hover_text("Go to the first comic published on or after this date");
+        if r.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+            if let Some(n) = date.ok().and_then(|date| self.data.first_comic_since(date)) {
+                self.n = n;
+                self.reset_img_transform();
+            }
+        }
+    }
+
     fn show_comic_img(&mut self, ui: &mut egui::Ui) {
         let Some(Some(comic)) = self.data.comics.get(self.n) else {
             return;
         };
 
-        if !comic.has_image_downloaded() {
-            ui.label(&comic.transcript);
+        let img_path = match comic.kind() {
+            ComicKind::Large if comic.has_large_image_downloaded() => comic.large_img_path(),
+            _ if comic.has_image_downloaded() => Some(comic.img_path()),
+            _ => None,
+        };
+        let Some(img_path) = img_path else {
+            match comic.kind() {
+                ComicKind::Static => ui.label(&comic.transcript),
+                _ if !comic.has_image() => ui.label("This comic has no image"),
+                _ => ui.label("Image not downloaded yet"),
+            };
             return;
-        }
+        };
 
-        let img_uri = format!("file://{}", comic.img_path().to_string_lossy());
+        let img_uri = format!("file://{}", img_path.to_string_lossy());
 
         let (id, rect) = ui.allocate_space(ui.available_size());
         let response = ui.interact(rect, id, egui::Sense::click_and_drag());
@@ -209,7 +478,7 @@ impl App {
         let Some(Some(comic)) = self.data.comics.get(self.n).cloned() else {
             ui.colored_label(ui.visuals().error_fg_color, "Error fetching comic");
             if ui.button("Try again").clicked() {
-                if let Err(e) = self.data.fetch_comic(self.n) {
+                if let Err(e) = self.data.fetch_comic(&self.client, self.n) {
                     eprintln!("error fetching comic: {e}");
                 }
             }
@@ -222,6 +491,11 @@ impl App {
                 |ui| ui.heading(&comic.title),
                 |ui| ui.heading(format!("#{}", comic.num)),
             );
+            match comic.date() {
+                Some(date) => ui.label(format!("Published {date}")),
+                None => ui.colored_label(ui.visuals().error_fg_color, "Invalid date"),
+            };
+            self.show_comic_kind(ui, &comic);
 
             ui.with_layout(egui::Layout::bottom_up(egui::Align::LEFT), |ui| {
                 ui.group(|ui| {
@@ -243,6 +517,43 @@ impl App {
         });
     }
 
+    /// Shows a note about comics that need more than a single image, with a
+    /// link to see them properly on xkcd.com.
+    fn show_comic_kind(&self, ui: &mut egui::Ui, comic: &store::Comic) {
+        let page_url = self.client.comic_page_url(comic.num);
+        let (note, link_text, url) = match comic.kind() {
+            ComicKind::Static => return,
+            ComicKind::Large => (
+                match comic.has_large_image_downloaded() {
+                    true => "Large comic; scroll to zoom and drag to pan.",
+                    false => "Large comic",
+                },
+                "Open full size",
+                match comic.link.is_empty() {
+                    true => page_url,
+                    false => comic.link.clone(),
+                },
+            ),
+            ComicKind::Animated if comic.img.ends_with(".gif") => {
+                ("Animated comic", "Open on xkcd.com", page_url)
+            }
+            ComicKind::Animated => (
+                "Animated comic; this is only one frame.",
+                "Watch on xkcd.com",
+                page_url,
+            ),
+            ComicKind::Interactive => (
+                "Interactive comic; this is only a preview.",
+                "Open interactive comic on xkcd.com",
+                page_url,
+            ),
+        };
+        ui.horizontal(|ui| {
+            ui.label(note);
+            ui.hyperlink_to(link_text, url);
+        });
+    }
+
     fn reset_img_transform(&mut self) {
         self.img_transform = TSTransform::IDENTITY;
     }
@@ -252,8 +563,8 @@ impl App {
 
         ui.group(|ui| {
             ui.columns(TIERS.len(), |uis| {
-                for i in 0..TIERS.len() {
-                    self.display_tier(&mut uis[i], i)
+                for (i, ui) in uis.iter_mut().enumerate() {
+                    self.display_tier(ui, i)
                 }
             });
         });
@@ -377,6 +688,10 @@ impl App {
             },
         );
 
+        // Arrow keys move the cursor while typing a date instead.
+        if ui.ctx().wants_keyboard_input() {
+            return;
+        }
         ui.input(|input| {
             if input.key_pressed(egui::Key::ArrowUp) && self.n > W {
                 self.n -= W;
@@ -397,6 +712,59 @@ impl App {
         });
     }
 
+    /// Asks what to do about a data store that couldn't be loaded.
+    fn show_load_error(&mut self, ctx: &egui::Context) {
+        let Some(load_error) = &self.load_error else {
+            return;
+        };
+        let mut recover = None;
+        let mut open_read_only = false;
+        let title = match self.locked {
+            true => "Data store in use",
+            false => "Error loading data store",
+        };
+        egui::Window::new(title)
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.colored_label(ui.visuals().error_fg_color, load_error);
+                if self.locked {
+                    ui.label("It can be viewed, but changes to it can't be saved.");
+                } else {
+                    ui.label("The data store will be moved aside before it is replaced.");
+                }
+                ui.horizontal(|ui| {
+                    if self.locked {
+                        open_read_only = ui.button("Open read-only").clicked();
+                    } else if let Some(backup) = &self.backup {
+                        let file_name = backup.file_name().unwrap_or_default().to_string_lossy();
+                        if ui.button(format!("Restore {file_name}")).clicked() {
+                            recover = Some(Some(backup.clone()));
+                        }
+                    }
+                    if !self.locked && ui.button("Start empty").clicked() {
+                        recover = Some(None);
+                    }
+                    if ui.button("Quit").clicked() {
+                        ui.ctx().send_viewport_cmd(egui::ViewportCommand::Close);
+                    }
+                });
+            });
+        let result = match recover {
+            Some(backup) => Store::recover(backup.as_deref()),
+            None if open_read_only => Store::load_read_only(),
+            None => return,
+        };
+        match result {
+            Ok(store) => {
+                self.data = store;
+                self.load_error = None;
+            }
+            Err(e) => self.set_load_error(e),
+        }
+    }
+
     fn comic_context_menu_contents(&self, ui: &mut egui::Ui, i: usize) {
         ui.hyperlink_to(format!("xkcd.com/{i}"), format!("https://xkcd.com/{i}"));
         ui.hyperlink_to(
@@ -408,6 +776,11 @@ impl App {
 
 impl eframe::App for App {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        if self.load_error.is_some() {
+            self.show_load_error(ctx);
+            return;
+        }
+
         egui::CentralPanel::default()
             .frame(
                 egui::Frame::central_panel(&ctx.style()).inner_margin(egui::Margin {
@@ -431,18 +804,24 @@ impl eframe::App for App {
                         })
                     })
                 });
-                if self.data.unsaved {
+                if self.data.is_read_only() {
+                    ui.with_layout(egui::Layout::right_to_left(egui::Align::TOP), |ui| {
+                        ui.label("Read-only");
+                    });
+                } else if self.data.unsaved {
                     ui.with_layout(egui::Layout::right_to_left(egui::Align::TOP), |ui| {
                         ui.label("Unsaved changes");
                     });
                 }
 
+                // Letters typed into a text box aren't tier shortcuts.
+                let is_typing = ui.ctx().wants_keyboard_input();
                 ui.input(|input| {
                     if input.modifiers.command_only() && input.key_pressed(egui::Key::S) {
                         self.data.save();
                     }
 
-                    if input.modifiers.is_none() {
+                    if input.modifiers.is_none() && !is_typing {
                         for (key, tier) in [
                             (egui::Key::U, 0),
                             (egui::Key::W, 1),
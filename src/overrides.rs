@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use eyre::{Result, WrapErr};
+use serde::Deserialize;
+
+use crate::store::{Comic, ComicKind};
+
+/// Overrides that ship with the program.
+const BUNDLED: &str = include_str!("overrides.json");
+
+static OVERRIDES: OnceLock<Overrides> = OnceLock::new();
+
+/// Corrections to comic metadata and images, by comic number.
+///
+/// Bundled overrides are read from `src/overrides.json`, and users can add
+/// their own in `overrides.json` in the working directory. A user override
+/// replaces the bundled one for the same comic.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(transparent)]
+pub struct Overrides(BTreeMap<usize, Override>);
+
+/// Correction to a single comic.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Override {
+    /// Whether to fetch the metadata from xkcd. If this is false, the comic
+    /// is made only from `metadata`.
+    pub fetch: bool,
+    /// Metadata fields to set, replacing the ones from xkcd.
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+    /// Image file to use instead of downloading `img`, relative to the working
+    /// directory.
+    pub image_file: Option<PathBuf>,
+    /// Kind of comic, for comics that can't be classified from their metadata.
+    pub kind: Option<ComicKind>,
+}
+impl Default for Override {
+    fn default() -> Self {
+        Self {
+            fetch: true,
+            metadata: serde_json::Map::new(),
+            image_file: None,
+            kind: None,
+        }
+    }
+}
+
+impl Overrides {
+    /// Loads the bundled overrides and the user's overrides file, if there is
+    /// one.
+    pub fn load() -> Result<Self> {
+        let mut overrides: Self =
+            serde_json::from_str(BUNDLED).wrap_err("error parsing bundled overrides")?;
+
+        let path = crate::util::overrides_path();
+        match std::fs::read_to_string(&path) {
+            Ok(json_string) => {
+                let user: Self = serde_json::from_str(&json_string)
+                    .wrap_err_with(|| format!("error parsing {}", path.display()))?;
+                overrides.0.extend(user.0);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("error reading {}", path.display()));
+            }
+        }
+
+        for (&n, o) in &overrides.0 {
+            o.apply(&mut Comic::default())
+                .wrap_err_with(|| format!("invalid override for comic #{n}"))?;
+        }
+
+        Ok(overrides)
+    }
+
+    /// Makes these the overrides returned by [`get()`].
+    pub fn install(self) {
+        if OVERRIDES.set(self).is_err() {
+            eprintln!("overrides were already loaded");
+        }
+    }
+}
+
+/// Returns the override for comic `n`, if there is one. If no overrides have
+/// been installed, only the bundled ones are used.
+pub fn get(n: usize) -> Option<&'static Override> {
+    OVERRIDES
+        .get_or_init(|| serde_json::from_str(BUNDLED).expect("error parsing bundled overrides"))
+        .0
+        .get(&n)
+}
+
+impl Override {
+    /// Returns the comic described by this override alone.
+    pub fn supply(&self, n: usize) -> Comic {
+        let mut comic = Comic::default();
+        self.apply(&mut comic)
+            .expect("overrides should have been checked when loading");
+        comic.num = n;
+        comic
+    }
+
+    /// Sets the fields of `comic` that this override replaces.
+    pub fn apply(&self, comic: &mut Comic) -> serde_json::Result<()> {
+        if self.metadata.is_empty() {
+            return Ok(());
+        }
+        let num = comic.num;
+        let serde_json::Value::Object(mut fields) = serde_json::to_value(&*comic)? else {
+            unreachable!("comics should serialize to objects");
+        };
+        fields.extend(self.metadata.clone());
+        *comic = serde_json::from_value(serde_json::Value::Object(fields))?;
+        comic.num = num;
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::download::{format_comic_list, Summary};
+use crate::fetch::{ErrorKind, FetchError};
+
+/// How to report progress while downloading.
+#[derive(clap::ValueEnum, Serialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum ProgressFormat {
+    /// Human-readable messages.
+    #[default]
+    Human,
+    /// One JSON event per line.
+    Json,
+}
+
+/// Step of fetching a comic.
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Metadata,
+    Image,
+    LargeImage,
+}
+
+/// Something that happened while downloading.
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// Planned what to download, without downloading anything.
+    Planned {
+        metadata: &'a [usize],
+        images: &'a [usize],
+        redownload: &'a [usize],
+    },
+    /// Started downloading comics.
+    Started { queued: usize, jobs: usize },
+    /// Fetched the metadata for a comic.
+    MetadataFetched { comic: usize, millis: u128 },
+    /// Downloaded the image for a comic.
+    ImageDownloaded {
+        comic: usize,
+        bytes: u64,
+        millis: u128,
+    },
+    /// Downloaded the bigger version of the image for a comic.
+    LargeImageDownloaded {
+        comic: usize,
+        bytes: u64,
+        millis: u128,
+    },
+    /// Something for a comic hasn't changed since we last downloaded it.
+    Unchanged { comic: usize, stage: Stage },
+    /// Saved the store partway through.
+    Checkpoint { done: usize },
+    /// Failed to fetch something for a comic.
+    Error {
+        comic: usize,
+        stage: Stage,
+        kind: ErrorKind,
+        message: &'a str,
+    },
+    /// Finished downloading comics.
+    Finished {
+        #[serde(flatten)]
+        summary: &'a Summary,
+        millis: u128,
+    },
+}
+
+/// Reports progress in the requested format.
+///
+/// Only one thread should report progress so that lines from different
+/// comics never get interleaved.
+#[derive(Debug)]
+pub struct Progress {
+    format: ProgressFormat,
+    start: Instant,
+}
+impl Progress {
+    pub fn new(format: ProgressFormat) -> Self {
+        Self {
+            format,
+            start: Instant::now(),
+        }
+    }
+
+    /// Prints a human-readable message. This is ignored when printing JSON.
+    pub fn info(&self, message: impl fmt::Display) {
+        if self.format == ProgressFormat::Human {
+            println!("{message}");
+        }
+    }
+
+    /// Reports an error for comic `n`.
+    pub fn error(&self, comic: usize, stage: Stage, e: &FetchError) {
+        self.emit(Event::Error {
+            comic,
+            stage,
+            kind: e.kind,
+            message: &e.message,
+        });
+    }
+
+    /// Reports that we are done, along with a summary of what changed.
+    pub fn finished(&self, summary: &Summary) {
+        self.emit(Event::Finished {
+            summary,
+            millis: self.start.elapsed().as_millis(),
+        });
+    }
+
+    pub fn emit(&self, event: Event<'_>) {
+        match self.format {
+            ProgressFormat::Human => print_human(&event),
+            ProgressFormat::Json => match serde_json::to_string(&event) {
+                Ok(line) => println!("{line}"),
+                Err(e) => eprintln!("error serializing progress event: {e}"),
+            },
+        }
+    }
+}
+
+fn print_human(event: &Event<'_>) {
+    match event {
+        Event::Planned {
+            metadata,
+            images,
+            redownload,
+        } => {
+            for (label, comics) in [
+                ("Would fetch metadata for", metadata),
+                ("Would download images for", images),
+                ("With --redownload, would download again", redownload),
+            ] {
+                match comics.len() {
+                    0 => println!("{label} 0 comics"),
+                    len => println!("{label} {len} comics: {}", format_comic_list(comics)),
+                }
+            }
+        }
+        Event::Started { queued, jobs } => {
+            println!("Fetching {queued} comics using {jobs} jobs ...");
+        }
+        Event::MetadataFetched { comic, .. } => println!("Fetched comic #{comic}"),
+        Event::ImageDownloaded {
+            comic,
+            bytes,
+            millis,
+        } => {
+            let secs = Duration::from_millis(*millis as u64).as_secs_f32();
+            println!("Downloaded image #{comic} ({bytes} bytes in {secs:.1}s)");
+        }
+        Event::LargeImageDownloaded {
+            comic,
+            bytes,
+            millis,
+        } => {
+            let secs = Duration::from_millis(*millis as u64).as_secs_f32();
+            println!("Downloaded large image #{comic} ({bytes} bytes in {secs:.1}s)");
+        }
+        Event::Unchanged {
+            comic,
+            stage: Stage::Metadata,
+        } => println!("Comic #{comic} is unchanged"),
+        Event::Unchanged {
+            comic,
+            stage: Stage::Image,
+        } => println!("Image #{comic} is unchanged"),
+        Event::Unchanged {
+            comic,
+            stage: Stage::LargeImage,
+        } => println!("Large image #{comic} is unchanged"),
+        Event::Checkpoint { done } => println!("Saved progress after {done} comics"),
+        Event::Error {
+            comic,
+            stage: Stage::Metadata,
+            kind,
+            message,
+        } => eprintln!("error fetching comic #{comic}: {kind}: {message}"),
+        Event::Error {
+            comic,
+            stage: Stage::Image,
+            kind,
+            message,
+        } => eprintln!("error downloading image #{comic}: {kind}: {message}"),
+        Event::Error {
+            comic,
+            stage: Stage::LargeImage,
+            kind,
+            message,
+        } => eprintln!("error downloading large image #{comic}: {kind}: {message}"),
+        Event::Finished { summary, .. } => summary.print(),
+    }
+}
@@ -0,0 +1,82 @@
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::store::Store;
+
+/// Set of comics chosen on the command line.
+///
+/// This is a comma-separated list of comic numbers (`404`), inclusive ranges
+/// (`1000..1100`), and tiers (`tier:S`), such as `1..10,404,tier:A`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection(Vec<Selector>);
+
+/// Largest number of comics that a single range may select. xkcd is nowhere
+/// near this, so anything bigger is a typo.
+const MAX_RANGE_LEN: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Selector {
+    Range(RangeInclusive<usize>),
+    Tier(u8),
+}
+
+impl FromStr for Selection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .map(|item| {
+                if let Some(tier_name) = item.strip_prefix("tier:") {
+                    let tier = crate::TIERS
+                        .iter()
+                        .position(|tier| tier.title.eq_ignore_ascii_case(tier_name))
+                        .ok_or_else(|| format!("unknown tier {tier_name:?}"))?;
+                    Ok(Selector::Tier(tier as u8))
+                } else if let Some((start, end)) = item.split_once("..") {
+                    let end = end.strip_prefix('=').unwrap_or(end);
+                    let (start, end) = (parse_num(start)?, parse_num(end)?);
+                    if start > end {
+                        return Err(format!("range {item:?} is backwards"));
+                    }
+                    if end - start >= MAX_RANGE_LEN {
+                        return Err(format!(
+                            "range {item:?} has more than {MAX_RANGE_LEN} comics"
+                        ));
+                    }
+                    Ok(Selector::Range(start..=end))
+                } else {
+                    let n = parse_num(item)?;
+                    Ok(Selector::Range(n..=n))
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(Selection)
+    }
+}
+
+fn parse_num(s: &str) -> Result<usize, String> {
+    s.trim()
+        .parse()
+        .map_err(|_| format!("invalid comic number {s:?}"))
+}
+
+impl Selection {
+    /// Returns the selected comic numbers in order, without duplicates.
+    pub fn comics(&self, store: &Store) -> Vec<usize> {
+        let mut comics: Vec<usize> = self
+            .0
+            .iter()
+            .flat_map(|selector| match selector {
+                Selector::Range(range) => range.clone().collect(),
+                Selector::Tier(tier) => (1..store.comics.len())
+                    .filter(|&i| store.get_tier_of_comic(i) == *tier)
+                    .collect::<Vec<_>>(),
+            })
+            .filter(|&n| n > 0)
+            .collect();
+        comics.sort();
+        comics.dedup();
+        comics
+    }
+}
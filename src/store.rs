@@ -1,39 +1,353 @@
-use std::path::PathBuf;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
 
-use eyre::{ensure, Result};
+use eyre::{bail, ensure, Result, WrapErr};
 use serde::{Deserialize, Serialize};
 
+use crate::date::{Date, DateRange};
+use crate::fetch::{Client, ErrorKind, FetchError, Fetched, RetryPolicy, Validators};
+use crate::lock::{Locked, StoreLock};
+
 /// Store of downloaded comics.
+///
+/// This is kept in an SQLite database, and only the comics that changed are
+/// written when saving. Older versions used a single JSON file, which is
+/// migrated the first time the store is loaded.
+///
+/// Only one process at a time can load the store for writing. Others can only
+/// load it read-only.
 #[derive(Serialize, Deserialize, Default)]
 #[serde(default)]
 pub struct Store {
     pub comics: Vec<Option<Comic>>,
     pub tier_assignments: Vec<u8>,
+    /// HTTP cache validators for each comic, indexed by comic number.
+    pub validators: Vec<ComicValidators>,
     #[serde(skip)]
     pub unsaved: bool,
+    /// Comics whose metadata, validators, or tier changed since the last save.
+    #[serde(skip)]
+    changed: BTreeSet<usize>,
+    /// Whether this store was loaded from disk, as opposed to made empty
+    /// because loading failed. Only loaded stores may be saved.
+    #[serde(skip)]
+    loaded: bool,
+    /// Whether the database has been backed up since it was loaded. Only the
+    /// first save backs it up, so that checkpoints during a long download
+    /// don't rotate out every backup from before it.
+    #[serde(skip)]
+    backed_up: bool,
+    /// Lock that keeps other processes from writing to the store, or `None`
+    /// if the store is read-only.
+    #[serde(skip)]
+    lock: Option<StoreLock>,
 }
 impl Store {
-    pub fn load() -> Self {
-        std::fs::read_to_string(crate::util::comics_json_path())
-            .ok()
-            .and_then(|json_string| serde_json::from_str(&json_string).ok())
-            .unwrap_or_default()
+    /// Loads the store, or returns an error if there is one but it can't be
+    /// read. The store is only empty if there isn't one yet.
+    ///
+    /// Returns a [`Locked`] error if another process is using the store.
+    pub fn load() -> Result<Self> {
+        Self::load_locked(StoreLock::acquire()?)
+    }
+
+    fn load_locked(lock: StoreLock) -> Result<Self> {
+        let mut store = Self::load_db()?;
+        store.lock = Some(lock);
+        store.finish_loading();
+        Ok(store)
+    }
+
+    /// Loads the store without locking it, so that it can be viewed while
+    /// another process is using it. The store can't be saved.
+    pub fn load_read_only() -> Result<Self> {
+        let db_path = crate::util::db_path();
+        let json_path = crate::util::comics_json_path();
+        let mut store = if db_path.exists() {
+            crate::db::read_store(&crate::db::open_read_only(&db_path)?)?
+        } else if json_path.exists() {
+            let json_string = std::fs::read_to_string(&json_path)?;
+            serde_json::from_str(&json_string)
+                .wrap_err_with(|| format!("error parsing {}", json_path.display()))?
+        } else {
+            Self::default()
+        };
+        store.finish_loading();
+        Ok(store)
+    }
+
+    fn finish_loading(&mut self) {
+        self.loaded = true;
+        self.report_invalid_dates();
+        self.repair_text();
+        self.apply_overrides();
+    }
+
+    /// Returns whether another process was using the store when it was
+    /// loaded, so that it can't be saved.
+    pub fn is_read_only(&self) -> bool {
+        self.lock.is_none()
+    }
+
+    /// Loads the store. If it can't be read and `force` is true, moves it
+    /// aside and restores the newest backup instead.
+    pub fn load_or_recover(force: bool) -> Result<Self> {
+        match Self::load() {
+            Ok(store) => Ok(store),
+            // Recovering would replace the store that the other process is
+            // using.
+            Err(e) if e.is::<Locked>() => Err(e),
+            Err(e) if force => {
+                eprintln!("error loading data store: {e:#}");
+                Self::recover(Self::newest_valid_backup().as_deref())
+            }
+            Err(e) => Err(e.wrap_err("error loading data store")),
+        }
+    }
+
+    /// Returns the newest backup of the store that can be loaded.
+    pub fn newest_valid_backup() -> Option<PathBuf> {
+        if let Some(backup) = crate::backup::list()
+            .into_iter()
+            .find(|backup| crate::db::check(backup).is_ok())
+        {
+            return Some(backup);
+        }
+        // Older versions had no backups other than the migrated JSON store.
+        let json_backup = crate::util::migrated_json_path();
+        let json_is_valid = std::fs::read_to_string(&json_backup)
+            .is_ok_and(|json_string| serde_json::from_str::<Store>(&json_string).is_ok());
+        json_is_valid.then_some(json_backup)
+    }
+
+    /// Moves a store that can't be loaded aside, so that it is never saved
+    /// over, and then restores `backup` or starts with an empty store.
+    pub fn recover(backup: Option<&Path>) -> Result<Self> {
+        let lock = StoreLock::acquire()?;
+
+        for path in [crate::util::db_path(), crate::util::comics_json_path()] {
+            if path.exists() {
+                let aside = crate::util::broken_path(&path);
+                std::fs::rename(&path, &aside)
+                    .wrap_err_with(|| format!("error moving {} aside", path.display()))?;
+                eprintln!("moved {} to {}", path.display(), aside.display());
+            }
+        }
+
+        match backup {
+            Some(backup) => {
+                let is_json = backup.to_string_lossy().ends_with(".migrated");
+                let dest = match is_json {
+                    true => crate::util::comics_json_path(),
+                    false => crate::util::db_path(),
+                };
+                copy_atomically(backup, &dest)
+                    .wrap_err_with(|| format!("error restoring {}", backup.display()))?;
+                eprintln!("restored data store from {}", backup.display());
+            }
+            None => eprintln!("no backup to restore; starting with an empty data store"),
+        }
+
+        Self::load_locked(lock)
+    }
+
+    /// Rolls the store back to `backup`. The current store is backed up first
+    /// so that this can be undone, or moved aside if it can't be loaded.
+    pub fn restore(backup: &Path) -> Result<Self> {
+        let lock = StoreLock::acquire()?;
+        crate::db::check(backup).wrap_err_with(|| format!("error reading {}", backup.display()))?;
+
+        let db_path = crate::util::db_path();
+        // Copy the backup before making a new one, which might delete it.
+        let tmp_path = db_path.with_extension("sqlite.restore");
+        std::fs::copy(backup, &tmp_path)?;
+        if db_path.exists() {
+            match crate::db::check(&db_path) {
+                Ok(()) => crate::backup::create(&crate::db::open(&db_path)?)?,
+                Err(_) => {
+                    let aside = crate::util::broken_path(&db_path);
+                    std::fs::rename(&db_path, &aside)?;
+                    eprintln!("moved {} to {}", db_path.display(), aside.display());
+                }
+            }
+        }
+        std::fs::rename(&tmp_path, &db_path)?;
+
+        Self::load_locked(lock)
+    }
+
+    /// Applies overrides that were added or changed since the comics were
+    /// saved, and adds comics that only come from overrides.
+    fn apply_overrides(&mut self) {
+        for n in 1..self.comics.len() {
+            let Some(o) = crate::overrides::get(n) else {
+                continue;
+            };
+            let comic = match &self.comics[n] {
+                Some(comic) => {
+                    let mut comic = comic.clone();
+                    if let Err(e) = o.apply(&mut comic) {
+                        eprintln!("error applying override for comic #{n}: {e}");
+                        continue;
+                    }
+                    comic
+                }
+                None if !o.fetch => o.supply(n),
+                None => continue,
+            };
+            if self.comics[n].as_ref() != Some(&comic) {
+                self.insert_comic(comic);
+                self.unsaved = true;
+            }
+        }
+    }
+
+    /// Repairs encoding damage in comics that were saved before we knew to
+    /// look for it.
+    fn repair_text(&mut self) {
+        let mut repaired = 0;
+        for comic in self.comics.iter_mut().flatten() {
+            if comic.repair_text() {
+                self.changed.insert(comic.num);
+                repaired += 1;
+            }
+        }
+        if repaired > 0 {
+            eprintln!("repaired text encoding of {repaired} comics");
+            self.unsaved = true;
+        }
+    }
+
+    /// Prints a warning for each comic whose date can't be parsed.
+    fn report_invalid_dates(&self) {
+        for comic in self.comics.iter().flatten() {
+            if comic.date().is_none() {
+                let Comic {
+                    num,
+                    year,
+                    month,
+                    day,
+                    ..
+                } = comic;
+                eprintln!(
+                    "comic #{num} has an invalid date: year {year:?}, month {month:?}, day {day:?}"
+                );
+            }
+        }
+    }
+
+    /// Loads the database, first migrating the JSON store to it if there is
+    /// no database yet.
+    fn load_db() -> Result<Self> {
+        let db_path = crate::util::db_path();
+        let json_path = crate::util::comics_json_path();
+        if !db_path.exists() && json_path.exists() {
+            let migrated_path = crate::util::migrated_json_path();
+            crate::db::migrate_from_json(&json_path, &db_path, &migrated_path)?;
+            eprintln!(
+                "moved data store from {} to {}",
+                json_path.display(),
+                db_path.display(),
+            );
+        }
+        if !db_path.exists() {
+            return Ok(Self::default());
+        }
+        crate::db::read_store(&crate::db::open(&db_path)?)
     }
 
     pub fn save(&mut self) {
-        let path = crate::util::comics_json_path();
-        let contents = serde_json::to_string(self).expect("error serializing data store");
-        match std::fs::write(path, contents) {
-            Ok(_) => self.unsaved = false,
-            Err(e) => eprintln!("error saving data store: {e}"),
+        match self.write_changes() {
+            Ok(()) => self.mark_saved(),
+            Err(e) => eprintln!("error saving data store: {e:#}"),
+        }
+    }
+
+    fn write_changes(&mut self) -> Result<()> {
+        let db_path = crate::util::db_path();
+        let json_path = crate::util::comics_json_path();
+        if !self.loaded && (db_path.exists() || json_path.exists()) {
+            bail!(
+                "refusing to save over {}, which couldn't be loaded",
+                db_path.display(),
+            );
+        }
+        if self.is_read_only() {
+            bail!("the data store is read-only because another process was using it");
+        }
+        if !db_path.exists() && json_path.exists() {
+            // Saving now would make a new database that hides the comics in
+            // the JSON store.
+            bail!("{} hasn't been migrated yet", json_path.display());
         }
+        if self.changed.is_empty() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(crate::util::cache_dir())?;
+        let is_new = !db_path.exists();
+        let mut conn = crate::db::open(&db_path)?;
+        if !is_new && !self.backed_up {
+            crate::backup::create(&conn)?;
+            self.backed_up = true;
+        }
+        // SQLite commits the whole transaction or none of it, even if we
+        // crash partway through.
+        crate::db::write_comics(&mut conn, self, self.changed.iter().copied())
+    }
+
+    /// Forgets about changes, as if they had been saved.
+    pub fn mark_saved(&mut self) {
+        self.changed.clear();
+        self.unsaved = false;
     }
 
-    pub fn has_comic(&self, i: usize) -> bool {
-        self.comics.get(i).is_some_and(|entry| entry.is_some())
+    pub fn get_comic(&self, i: usize) -> Option<&Comic> {
+        self.comics.get(i)?.as_ref()
     }
 
-    pub fn fetch_comic(&mut self, i: usize) -> Result<&Comic> {
+    /// Returns the number of the newest comic in the store, or 0 if the store
+    /// is empty.
+    pub fn latest_comic_num(&self) -> usize {
+        self.comics
+            .iter()
+            .rposition(|entry| entry.is_some())
+            .unwrap_or(0)
+    }
+
+    /// Returns the comics in the store in the order they were published.
+    /// Comics with invalid dates are left out.
+    pub fn comics_by_date(&self) -> Vec<&Comic> {
+        let mut comics: Vec<&Comic> = self
+            .comics
+            .iter()
+            .flatten()
+            .filter(|comic| comic.date().is_some())
+            .collect();
+        comics.sort_by_key(|comic| (comic.date(), comic.num));
+        comics
+    }
+
+    /// Returns the numbers of the comics in the store that were published in
+    /// `range`, in the order they were published.
+    pub fn comics_in_range(&self, range: DateRange) -> Vec<usize> {
+        self.comics_by_date()
+            .into_iter()
+            .filter(|comic| comic.is_published_in(range))
+            .map(|comic| comic.num)
+            .collect()
+    }
+
+    /// Returns the number of the first comic published on or after `date`.
+    pub fn first_comic_since(&self, date: Date) -> Option<usize> {
+        self.comics_in_range(DateRange {
+            since: Some(date),
+            until: None,
+        })
+        .first()
+        .copied()
+    }
+
+    pub fn fetch_comic(&mut self, client: &Client, i: usize) -> Result<&Comic> {
         ensure!(i > 0, "comic #0 doesn't exist");
 
         while self.comics.len() <= i {
@@ -42,7 +356,37 @@ impl Store {
 
         match &self.comics[i] {
             Some(_) => Ok(self.comics[i].as_ref().unwrap()),
-            None => Ok(self.comics[i].insert(Comic::get_nth(i)?)),
+            None => {
+                let comic = Comic::get_nth(client, i)?;
+                self.changed.insert(i);
+                Ok(self.comics[i].insert(comic))
+            }
+        }
+    }
+
+    pub fn insert_comic(&mut self, comic: Comic) {
+        let i = comic.num;
+        if self.comics.len() <= i {
+            self.comics.resize(i + 1, None);
+        }
+        if self.comics[i].as_ref() != Some(&comic) {
+            self.comics[i] = Some(comic);
+            self.changed.insert(i);
+        }
+    }
+
+    pub fn get_validators(&self, i: usize) -> ComicValidators {
+        self.validators.get(i).cloned().unwrap_or_default()
+    }
+    /// Calls `f` to update the validators for comic `i`.
+    pub fn update_validators(&mut self, i: usize, f: impl FnOnce(&mut ComicValidators)) {
+        if self.validators.len() <= i {
+            self.validators.resize(i + 1, ComicValidators::default());
+        }
+        let old = self.validators[i].clone();
+        f(&mut self.validators[i]);
+        if self.validators[i] != old {
+            self.changed.insert(i);
         }
     }
 
@@ -60,11 +404,40 @@ impl Store {
         self.ensure_tiers_exist();
         if i < self.comics.len() {
             self.tier_assignments[i] = tier;
+            self.changed.insert(i);
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// HTTP cache validators for a comic's metadata and image.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ComicValidators {
+    pub metadata: Validators,
+    pub image: Validators,
+    pub large_image: Validators,
+}
+
+/// What a comic is like, which decides how to show it.
+///
+/// Only the image and the large image are downloaded. Comics that need more
+/// than that, such as the frames of an animation or the scripts of an
+/// interactive comic, link to xkcd.com instead.
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ComicKind {
+    /// Single image.
+    Static,
+    /// Image with a bigger version linked from the comic.
+    Large,
+    /// Animation, either as a GIF or using JavaScript on xkcd.com.
+    Animated,
+    /// Comic that only works on xkcd.com. The image, if there is one, is just
+    /// a preview.
+    Interactive,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct Comic {
     pub num: usize,
 
@@ -81,56 +454,243 @@ pub struct Comic {
 
     pub safe_title: String,
     pub transcript: String,
+
+    /// Extra HTML, scripts, and styles for interactive comics.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extra_parts: Option<serde_json::Value>,
+
+    /// Text as xkcd sent it, if it had to be repaired because of encoding
+    /// damage.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_safe_title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_alt: Option<String>,
+
+    /// Fields from the xkcd API that we don't use, kept so that they aren't
+    /// lost when saving the store.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 impl Comic {
+    pub fn kind(&self) -> ComicKind {
+        if let Some(kind) = crate::overrides::get(self.num).and_then(|o| o.kind) {
+            kind
+        } else if self.extra_parts.is_some() {
+            ComicKind::Interactive
+        } else if self.img.ends_with(".gif") {
+            ComicKind::Animated
+        } else if self.large_img_url().is_some()
+            || self.link.contains("/large/")
+            || self.link.contains("/huge/")
+        {
+            ComicKind::Large
+        } else {
+            ComicKind::Static
+        }
+    }
+
+    /// Returns the date the comic was published, or `None` if it is invalid.
+    pub fn date(&self) -> Option<Date> {
+        Date::new(
+            self.year.trim().parse().ok()?,
+            self.month.trim().parse().ok()?,
+            self.day.trim().parse().ok()?,
+        )
+    }
+
+    /// Returns whether the comic was published in `range`. Comics with invalid
+    /// dates are only in unbounded ranges.
+    pub fn is_published_in(&self, range: DateRange) -> bool {
+        range.is_unbounded() || self.date().is_some_and(|date| range.contains(date))
+    }
+
+    /// Repairs text that xkcd double-encoded, keeping the original in the
+    /// `raw_*` fields. Returns whether anything changed.
+    pub fn repair_text(&mut self) -> bool {
+        let mut changed = false;
+        for (text, raw) in [
+            (&mut self.title, &mut self.raw_title),
+            (&mut self.safe_title, &mut self.raw_safe_title),
+            (&mut self.alt, &mut self.raw_alt),
+        ] {
+            if let Some(repaired) = crate::text::repair_mojibake(text) {
+                let original = std::mem::replace(text, repaired);
+                raw.get_or_insert(original);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Returns the file that an override supplies in place of the image, if
+    /// there is one.
+    pub fn image_file(&self) -> Option<&'static Path> {
+        crate::overrides::get(self.num)?.image_file.as_deref()
+    }
+
+    /// Returns whether the comic has an image, either on xkcd or from an
+    /// override. Some interactive comics have none.
+    pub fn has_image(&self) -> bool {
+        !self.img.is_empty() || self.image_file().is_some()
+    }
+
     pub fn img_path(&self) -> PathBuf {
-        let image_ext = self.img.rsplit_once('.').unwrap_or((&self.img, "")).1;
-        crate::util::img_path(self.num, image_ext)
+        let ext = match self.image_file() {
+            Some(image_file) => image_file.extension().unwrap_or_default().to_str(),
+            None => Some(url_extension(&self.img)),
+        };
+        crate::util::img_path(self.num, ext.unwrap_or_default())
     }
 
     pub fn has_image_downloaded(&self) -> bool {
         std::fs::exists(self.img_path()).unwrap_or(false)
     }
 
-    pub fn download_image(&self) -> Result<()> {
-        std::fs::create_dir_all("cache/img")?;
+    /// Returns the URL of the bigger version of the image, if the comic links
+    /// straight to one.
+    pub fn large_img_url(&self) -> Option<&str> {
+        let ext = url_extension(&self.link);
+        ["png", "jpg", "jpeg", "gif"]
+            .iter()
+            .any(|image_ext| ext.eq_ignore_ascii_case(image_ext))
+            .then_some(self.link.as_str())
+    }
+
+    pub fn large_img_path(&self) -> Option<PathBuf> {
+        let url = self.large_img_url()?;
+        Some(crate::util::large_img_path(self.num, url_extension(url)))
+    }
+
+    pub fn has_large_image_downloaded(&self) -> bool {
+        self.large_img_path()
+            .is_some_and(|path| std::fs::exists(path).unwrap_or(false))
+    }
+
+    /// Returns whether every image that the comic has is downloaded.
+    pub fn has_all_images_downloaded(&self) -> bool {
+        (!self.has_image() || self.has_image_downloaded())
+            && (self.large_img_url().is_none() || self.has_large_image_downloaded())
+    }
+
+    /// Downloads the comic's image, preferring the `_2x` version if there is
+    /// one. Returns the size of the image, unless it hasn't changed since the
+    /// response that `validators` came from.
+    pub fn download_image(
+        &self,
+        client: &Client,
+        retry: &RetryPolicy,
+        validators: &Validators,
+    ) -> Result<Fetched<u64>, FetchError> {
+        if !self.has_image() {
+            return Err(FetchError::new(ErrorKind::NotFound, "comic has no image"));
+        }
+
+        std::fs::create_dir_all(crate::util::img_dir()).map_err(FetchError::disk)?;
+
+        let path = self.img_path();
+        if let Some(image_file) = self.image_file() {
+            copy_atomically(image_file, &path).map_err(FetchError::disk)?;
+            let bytes = std::fs::metadata(&path).map_err(FetchError::disk)?.len();
+            return Ok(Fetched::Modified(bytes, Validators::default()));
+        }
+        // Validators only make sense if we still have the image they are for.
+        let validators = match self.has_image_downloaded() {
+            true => validators,
+            false => &Validators::default(),
+        };
+        let download =
+            |url: &str| retry.run(|| client.download_file(&client.img_url(url), &path, validators));
+
+        // Most comics don't have a `_2x` version, so don't bother reporting
+        // errors for it.
+        match self.img_2x().map(|url| download(&url)) {
+            Some(Ok(fetched)) => Ok(fetched),
+            _ => download(&self.img),
+        }
+    }
 
-        let mut buffer = vec![];
-        ureq::get(&self.img_2x().unwrap_or_default())
-            .call()
-            .or_else(|_| ureq::get(&self.img).call())?
-            .into_reader()
-            .read_to_end(&mut buffer)?;
+    /// Downloads the bigger version of the comic's image. Returns the size of
+    /// the image, unless it hasn't changed since the response that
+    /// `validators` came from.
+    pub fn download_large_image(
+        &self,
+        client: &Client,
+        retry: &RetryPolicy,
+        validators: &Validators,
+    ) -> Result<Fetched<u64>, FetchError> {
+        let (Some(url), Some(path)) = (self.large_img_url(), self.large_img_path()) else {
+            return Err(FetchError::new(
+                ErrorKind::NotFound,
+                "comic has no large image",
+            ));
+        };
 
-        std::fs::write(self.img_path(), buffer)?;
+        std::fs::create_dir_all(crate::util::img_dir()).map_err(FetchError::disk)?;
 
-        Ok(())
+        let validators = match self.has_large_image_downloaded() {
+            true => validators,
+            false => &Validators::default(),
+        };
+        retry.run(|| client.download_file(&client.img_url(url), &path, validators))
     }
 
-    pub fn get_from_url(url: &str) -> Result<Comic> {
-        Ok(ureq::get(url).call()?.into_json()?)
+    pub fn get_from_url(client: &Client, url: &str) -> Result<Comic, FetchError> {
+        client.get_json(url)
     }
-    fn get_nth(n: usize) -> Result<Comic> {
-        if n == 404 {
-            return Ok(Comic {
-                num: 404,
-                year: "2008".to_owned(),
-                month: "4".to_owned(),
-                day: "1".to_owned(),
-                title: "404 Not Found".to_owned(),
-                img: String::new(),
-                alt: "I have always been of the opinion that http://xkcd.com/404/ is an actual comic, if a slightly avant-garde one. I actually went out of my way to modify the 'random' button to include it, but that annoyed too many people—most of whom reasonably assumed it was a bug—and I eventually undid it.".to_owned(),
-                link: String::new(),
-                news: String::new(),
-                safe_title: "404 Not Found".to_owned(),
-                transcript: "nginx".to_owned(),
-            });
+    pub fn get_nth(client: &Client, n: usize) -> Result<Comic, FetchError> {
+        let fetched = Self::get_nth_if_modified(client, n, &Validators::default())?;
+        fetched
+            .modified()
+            .map(|(comic, _)| comic)
+            .ok_or_else(|| FetchError::new(ErrorKind::ClientError, "unexpected 304 response"))
+    }
+    /// Fetches comic `n` unless it hasn't changed since the response that
+    /// `validators` came from.
+    pub fn get_nth_if_modified(
+        client: &Client,
+        n: usize,
+        validators: &Validators,
+    ) -> Result<Fetched<Comic>, FetchError> {
+        let o = crate::overrides::get(n);
+        if let Some(o) = o.filter(|o| !o.fetch) {
+            return Ok(Fetched::Modified(o.supply(n), Validators::default()));
         }
 
-        Self::get_from_url(&format!("https://xkcd.com/{n}/info.0.json"))
+        let fetched: Fetched<Comic> =
+            client.get_json_if_modified(&client.comic_url(n), validators)?;
+        Ok(match fetched {
+            Fetched::Modified(mut comic, validators) => {
+                comic.repair_text();
+                if let Some(o) = o {
+                    o.apply(&mut comic).map_err(|e| {
+                        FetchError::new(ErrorKind::Decode, format!("error applying override: {e}"))
+                    })?;
+                }
+                Fetched::Modified(comic, validators)
+            }
+            Fetched::NotModified => Fetched::NotModified,
+        })
     }
 
     fn img_2x(&self) -> Option<String> {
         Some(self.img.strip_suffix(".png")?.to_owned() + "_2x.png")
     }
 }
+
+/// Returns the extension of the file that `url` points to, or an empty string
+/// if there isn't one.
+fn url_extension(url: &str) -> &str {
+    let file_name = url.rsplit('/').next().unwrap_or(url);
+    file_name.rsplit_once('.').map_or("", |(_, ext)| ext)
+}
+
+/// Copies `from` to `to` through a temporary file, so that `to` is never left
+/// half-written.
+fn copy_atomically(from: &Path, to: &Path) -> std::io::Result<()> {
+    let mut tmp_path = to.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    std::fs::copy(from, &tmp_path)?;
+    std::fs::rename(&tmp_path, to)
+}
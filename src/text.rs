@@ -0,0 +1,96 @@
+/// Characters that Windows-1252 puts in the bytes `0x80..=0x9F`, which
+/// Latin-1 leaves as control characters. `None` marks bytes that Windows-1252
+/// doesn't use.
+const WINDOWS_1252_HIGH: [Option<char>; 32] = [
+    Some('€'),
+    None,
+    Some('‚'),
+    Some('ƒ'),
+    Some('„'),
+    Some('…'),
+    Some('†'),
+    Some('‡'),
+    Some('ˆ'),
+    Some('‰'),
+    Some('Š'),
+    Some('‹'),
+    Some('Œ'),
+    None,
+    Some('Ž'),
+    None,
+    None,
+    Some('‘'),
+    Some('’'),
+    Some('“'),
+    Some('”'),
+    Some('•'),
+    Some('–'),
+    Some('—'),
+    Some('˜'),
+    Some('™'),
+    Some('š'),
+    Some('›'),
+    Some('œ'),
+    None,
+    Some('ž'),
+    Some('Ÿ'),
+];
+
+/// Repairs text that was encoded as UTF-8 and then decoded as Latin-1 or
+/// Windows-1252, possibly more than once, such as `Ã©` instead of `é`.
+/// Returns `None` if the text doesn't look damaged.
+pub fn repair_mojibake(s: &str) -> Option<String> {
+    let mut repaired = s.to_owned();
+    while let Some(once) = repair_once(&repaired) {
+        repaired = once;
+    }
+    (repaired != s).then_some(repaired)
+}
+
+fn repair_once(s: &str) -> Option<String> {
+    if s.is_ascii() {
+        return None;
+    }
+    let bytes = s
+        .chars()
+        .map(|c| match u8::try_from(c) {
+            Ok(byte) => Some(byte),
+            Err(_) => WINDOWS_1252_HIGH
+                .iter()
+                .position(|&high| high == Some(c))
+                .map(|i| 0x80 + i as u8),
+        })
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repairs_latin_1_damage() {
+        assert_eq!(repair_mojibake("TÃ©st").as_deref(), Some("Tést"));
+    }
+
+    #[test]
+    fn repairs_repeated_damage() {
+        assert_eq!(repair_mojibake("TÃƒÂ©st").as_deref(), Some("Tést"));
+    }
+
+    #[test]
+    fn repairs_windows_1252_damage() {
+        assert_eq!(repair_mojibake("itâ€™s").as_deref(), Some("it’s"));
+        assert_eq!(
+            repair_mojibake("â€œquotedâ€\u{9d}").as_deref(),
+            Some("“quoted”")
+        );
+    }
+
+    #[test]
+    fn leaves_correct_text_alone() {
+        for s in ["café", "naïve", "5€", "it’s", "plain ASCII", ""] {
+            assert_eq!(repair_mojibake(s), None, "{s:?}");
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 /// Returns the directory of the current EXE.
 pub fn main_dir() -> PathBuf {
@@ -19,6 +19,46 @@ pub fn img_path(n: usize, ext: &str) -> PathBuf {
     img_dir().join(format!("{n}.{ext}"))
 }
 
+pub fn large_img_path(n: usize, ext: &str) -> PathBuf {
+    img_dir().join(format!("{n}-large.{ext}"))
+}
+
+pub fn db_path() -> PathBuf {
+    cache_dir().join("comics.sqlite")
+}
+
+pub fn lock_path() -> PathBuf {
+    cache_dir().join(".lock")
+}
+
+pub fn backup_dir() -> PathBuf {
+    cache_dir().join("backups")
+}
+
 pub fn comics_json_path() -> PathBuf {
     cache_dir().join("comics.json")
 }
+
+/// Returns where the JSON store is moved after it has been migrated to the
+/// database.
+pub fn migrated_json_path() -> PathBuf {
+    cache_dir().join("comics.json.migrated")
+}
+
+/// Returns an unused path to move the unreadable file at `path` to.
+pub fn broken_path(path: &Path) -> PathBuf {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let mut broken = path.as_os_str().to_owned();
+    broken.push(format!(".broken-{timestamp}"));
+    PathBuf::from(broken)
+}
+
+pub fn config_path() -> PathBuf {
+    main_dir().join("config.json")
+}
+
+pub fn overrides_path() -> PathBuf {
+    main_dir().join("overrides.json")
+}
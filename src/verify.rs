@@ -0,0 +1,78 @@
+use std::path::Path;
+
+use eyre::{ensure, eyre, Result};
+use image::ImageFormat;
+
+use crate::download::{download_comics, format_comic_list, DownloadOptions};
+use crate::fetch::{Client, ErrorKind, FetchError};
+use crate::progress::{Progress, Stage};
+use crate::store::Store;
+
+/// Checks that every downloaded image can be decoded and is in the format that
+/// its extension says. Invalid images are deleted and downloaded again.
+pub fn verify_images(store: &mut Store, client: &Client, opts: DownloadOptions) -> Result<()> {
+    let progress = Progress::new(opts.progress);
+
+    progress.info("Verifying images ...");
+    let mut checked = 0;
+    let mut invalid = vec![];
+    for comic in store.comics.iter().flatten() {
+        let images = [
+            (Stage::Image, Some(comic.img_path())),
+            (Stage::LargeImage, comic.large_img_path()),
+        ];
+        for (stage, path) in images {
+            let Some(path) = path.filter(|path| path.exists()) else {
+                continue;
+            };
+            checked += 1;
+            if let Err(e) = verify_image(&path) {
+                let e = FetchError::new(ErrorKind::Decode, format!("invalid image: {e}"));
+                progress.error(comic.num, stage, &e);
+                invalid.push((comic.num, path));
+            }
+        }
+    }
+
+    progress.info(format!("{} of {checked} images are invalid", invalid.len()));
+    if invalid.is_empty() {
+        return Ok(());
+    }
+    if opts.dry_run {
+        let mut comics: Vec<usize> = invalid.iter().map(|&(n, _)| n).collect();
+        comics.dedup();
+        progress.info(format!(
+            "Would delete and download again images for {} comics: {}",
+            comics.len(),
+            format_comic_list(&comics),
+        ));
+        return Ok(());
+    }
+
+    for (_, path) in &invalid {
+        std::fs::remove_file(path)?;
+    }
+    let mut invalid: Vec<usize> = invalid.into_iter().map(|(n, _)| n).collect();
+    invalid.dedup();
+
+    progress.info("Downloading invalid images again ...");
+    let summary = download_comics(store, client, invalid, &opts, &progress);
+    progress.finished(&summary);
+
+    store.save();
+
+    Ok(())
+}
+
+fn verify_image(path: &Path) -> Result<()> {
+    let bytes = std::fs::read(path)?;
+    let expected = ImageFormat::from_path(path)?;
+    // An HTML error page served in place of an image fails here.
+    let actual = image::guess_format(&bytes).map_err(|_| eyre!("not an image"))?;
+    ensure!(
+        actual == expected,
+        "file is {actual:?} but extension is {expected:?}",
+    );
+    image::load_from_memory_with_format(&bytes, actual)?;
+    Ok(())
+}